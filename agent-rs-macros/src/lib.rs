@@ -0,0 +1,338 @@
+//! Procedural macros of agent-rs.
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, Expr, ExprLit, FnArg, GenericArgument, Ident, ItemFn, Lit, LitStr, Meta,
+    Pat, PathArguments, ReturnType, Type,
+};
+
+/// Turns a documented function into an action.
+///
+/// ```ignore
+/// /// Converts a temperature to Fahrenheit.
+/// ///
+/// /// # Arguments
+/// /// * `celsius` - Temperature in degrees Celsius
+/// #[action]
+/// async fn to_fahrenheit(celsius: f64) -> String {
+///     format!("{}", celsius * 9.0 / 5.0 + 32.0)
+/// }
+/// ```
+///
+/// generates a `ToFahrenheitAction` implementing `Action`, named after the function (or
+/// `#[action(name = "...")]`) and described by its doc comment. Parameter descriptions come from
+/// the `# Arguments` section. Arguments are deserialized from the tool call, so they have to be
+/// owned types, e.g. `String` rather than `&str`; `Option` arguments are optional. The return
+/// value is turned into the observation with `IntoObservation`.
+/// The function must be in the `agent-rs` crate, which provides `crate::actions`.
+#[proc_macro_attribute]
+pub fn action(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut name = None;
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("name") {
+            name = Some(meta.value()?.parse::<LitStr>()?.value());
+            Ok(())
+        } else {
+            Err(meta.error("unsupported action property, expected `name`"))
+        }
+    });
+    parse_macro_input!(attr with parser);
+    let function = parse_macro_input!(item as ItemFn);
+    match expand(function, name) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn expand(function: ItemFn, name: Option<String>) -> syn::Result<proc_macro2::TokenStream> {
+    let fn_ident = &function.sig.ident;
+    let name = name.unwrap_or_else(|| fn_ident.to_string());
+    let struct_ident = format_ident!("{}Action", to_camel_case(&fn_ident.to_string()));
+    let vis = &function.vis;
+    let docs = Docs::parse(&function);
+    let output_schema = match &function.sig.output {
+        ReturnType::Default => quote! { serde_json::json!({ "type": "null" }) },
+        ReturnType::Type(_, ty) => schema(ty),
+    };
+
+    let mut parameters = Vec::new();
+    let mut bindings = Vec::new();
+    let mut arguments = Vec::new();
+    for input in &function.sig.inputs {
+        let FnArg::Typed(input) = input else {
+            return Err(syn::Error::new_spanned(input, "actions cannot take `self`"));
+        };
+        let Pat::Ident(pat) = &*input.pat else {
+            return Err(syn::Error::new_spanned(&input.pat, "action arguments must be plain identifiers"));
+        };
+        let ident = Ident::new(&pat.ident.to_string(), Span::call_site());
+        let key = ident.to_string();
+        let ty = &input.ty;
+        if let Type::Reference(reference) = option_inner(ty).unwrap_or(ty) {
+            let owned = match &*reference.elem {
+                Type::Path(path) if path.path.is_ident("str") => "String".to_string(),
+                Type::Slice(slice) => {
+                    let elem = &slice.elem;
+                    format!("Vec<{}>", quote!(#elem))
+                }
+                elem => quote!(#elem).to_string(),
+            };
+            return Err(syn::Error::new_spanned(
+                reference,
+                format!("action arguments are deserialized and must be owned, use `{}` instead", owned),
+            ));
+        }
+        let param_schema = schema(ty);
+        let required = option_inner(ty).is_none();
+        let description = docs.argument(&key);
+        parameters.push(quote! {
+            crate::actions::Parameter {
+                name: #key.to_string(),
+                description: #description.to_string(),
+                schema: #param_schema,
+                required: #required,
+            }
+        });
+        let missing = if option_inner(ty).is_some() {
+            quote! { None }
+        } else {
+            quote! {
+                return crate::observation::Observation::error(format!("Missing required input: {}", #key))
+            }
+        };
+        let value_ty = option_inner(ty).unwrap_or(ty);
+        bindings.push(quote! {
+            let #ident: #ty = match crate::actions::input_value::<#value_ty>(&inputs, #key) {
+                Ok(Some(value)) => value.into(),
+                Ok(None) => #missing,
+                Err(error) => return crate::observation::Observation::error(error),
+            };
+        });
+        arguments.push(ident);
+    }
+
+    let call = if function.sig.asyncness.is_some() {
+        quote! { #fn_ident(#(#arguments),*).await }
+    } else {
+        quote! { #fn_ident(#(#arguments),*) }
+    };
+    let description = docs.description;
+    let log = format!("{}.act() called", struct_ident);
+
+    Ok(quote! {
+        #function
+
+        #vis struct #struct_ident {
+            pub info: crate::actions::ActionBase,
+        }
+
+        impl #struct_ident {
+            pub fn new() -> Self {
+                Self {
+                    info: crate::actions::ActionBase {
+                        name: #name.to_string(),
+                        description: #description.to_string(),
+                        parameters: vec![#(#parameters),*],
+                        output_schema: #output_schema,
+                    },
+                }
+            }
+        }
+
+        impl Default for #struct_ident {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        #[async_trait::async_trait]
+        impl crate::actions::Action for #struct_ident {
+            fn get_info(&self) -> &crate::actions::ActionBase {
+                &self.info
+            }
+
+            fn get_parameters(&self) -> &Vec<crate::actions::Parameter> {
+                &self.info.parameters
+            }
+
+            async fn act(&self, inputs: Vec<crate::actions::ActionInput>) -> crate::observation::Observation {
+                tracing::info!(#log);
+                let inputs = self.prepare_inputs(inputs);
+                #(#bindings)*
+                crate::actions::IntoObservation::into_observation(#call)
+            }
+        }
+    })
+}
+
+/// Description of the function and of its arguments, from its doc comment.
+struct Docs {
+    description: String,
+    arguments: Vec<(String, String)>,
+}
+
+impl Docs {
+    fn parse(function: &ItemFn) -> Self {
+        let lines: Vec<String> = function
+            .attrs
+            .iter()
+            .filter_map(|attr| match &attr.meta {
+                Meta::NameValue(nv) if nv.path.is_ident("doc") => match &nv.value {
+                    Expr::Lit(ExprLit { lit: Lit::Str(s), .. }) => Some(s.value().trim().to_string()),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect();
+        let mut description = Vec::new();
+        let mut arguments = Vec::new();
+        let mut in_arguments = false;
+        for line in &lines {
+            if line.starts_with('#') {
+                in_arguments = line.trim_start_matches('#').trim().eq_ignore_ascii_case("arguments");
+                continue;
+            }
+            if !in_arguments {
+                description.push(line.as_str());
+                continue;
+            }
+            // `* `name` - description`
+            let item = line.trim_start_matches(['*', '-']).trim();
+            let Some(rest) = item.strip_prefix('`') else {
+                continue;
+            };
+            if let Some((name, doc)) = rest.split_once('`') {
+                let doc = doc.trim().trim_start_matches(['-', ':']).trim();
+                arguments.push((name.to_string(), doc.to_string()));
+            }
+        }
+        Self {
+            description: description.join(" ").split_whitespace().collect::<Vec<_>>().join(" "),
+            arguments,
+        }
+    }
+
+    fn argument(&self, name: &str) -> String {
+        self.arguments
+            .iter()
+            .find(|(argument, _)| argument == name)
+            .map(|(_, doc)| doc.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// The `T` of an `Option<T>`.
+fn option_inner(ty: &Type) -> Option<&Type> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    if segment.ident != "Option" {
+        return None;
+    }
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) => match args.args.first()? {
+            GenericArgument::Type(inner) => Some(inner),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Builds the JSON Schema of a Rust type.
+fn schema(ty: &Type) -> proc_macro2::TokenStream {
+    if let Some(inner) = option_inner(ty) {
+        return schema(inner);
+    }
+    let object = quote! { serde_json::json!({ "type": "object" }) };
+    let Type::Path(path) = ty else {
+        return object;
+    };
+    let Some(segment) = path.path.segments.last() else {
+        return object;
+    };
+    let type_arguments: Vec<&Type> = match &segment.arguments {
+        PathArguments::AngleBracketed(args) => args
+            .args
+            .iter()
+            .filter_map(|arg| match arg {
+                GenericArgument::Type(ty) => Some(ty),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    match segment.ident.to_string().as_str() {
+        "String" | "char" => quote! { serde_json::json!({ "type": "string" }) },
+        "i8" | "i16" | "i32" | "i64" | "i128" | "isize" => quote! { serde_json::json!({ "type": "integer" }) },
+        "u8" | "u16" | "u32" | "u64" | "u128" | "usize" => {
+            quote! { serde_json::json!({ "type": "integer", "minimum": 0 }) }
+        }
+        "f32" | "f64" => quote! { serde_json::json!({ "type": "number" }) },
+        "bool" => quote! { serde_json::json!({ "type": "boolean" }) },
+        "Value" => quote! { serde_json::json!({}) },
+        "Vec" | "VecDeque" | "HashSet" | "BTreeSet" => match type_arguments.first() {
+            Some(item) => {
+                let items = schema(item);
+                quote! { serde_json::json!({ "type": "array", "items": #items }) }
+            }
+            None => quote! { serde_json::json!({ "type": "array" }) },
+        },
+        "HashMap" | "BTreeMap" => match type_arguments.get(1) {
+            Some(value) => {
+                let values = schema(value);
+                quote! { serde_json::json!({ "type": "object", "additionalProperties": #values }) }
+            }
+            None => object,
+        },
+        "Result" => match type_arguments.first() {
+            Some(ok) => schema(ok),
+            None => object,
+        },
+        _ => object,
+    }
+}
+
+fn to_camel_case(name: &str) -> String {
+    name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
+
+    fn error(function: ItemFn) -> String {
+        expand(function, None).expect_err("the expansion should fail").to_string()
+    }
+
+    #[test]
+    fn rejects_borrowed_arguments() {
+        let message = error(parse_quote! {
+            fn search(query: &str) -> String { query.to_string() }
+        });
+        assert_eq!(message, "action arguments are deserialized and must be owned, use `String` instead");
+
+        let message = error(parse_quote! {
+            fn search(query: String, tags: Option<&[String]>) -> String { query }
+        });
+        assert_eq!(message, "action arguments are deserialized and must be owned, use `Vec<String>` instead");
+    }
+
+    #[test]
+    fn accepts_owned_arguments() {
+        let function: ItemFn = parse_quote! {
+            fn search(query: String, limit: Option<usize>) -> String { query }
+        };
+        assert!(expand(function, None).is_ok());
+    }
+}
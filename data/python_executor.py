@@ -0,0 +1,357 @@
+# Driver of the Python interpreter used by code agents (see src/executor.rs).
+#
+# Requests and replies are exchanged as JSON lines over the process' stdin/stdout:
+#   <- {"type": "started"} or {"type": "failed", "error": ...}, once the sandbox is set up
+#   -> {"type": "execute", "code": ...}
+#   <- {"type": "tool_call", "name": ..., "arguments": {...}}
+#   -> {"type": "tool_result", "result": ..., "error": ...}
+#   <- {"type": "result", "logs": ..., "output": ..., "error": ..., "is_final_answer": ..., "attachments": [...]}
+#
+# The code runs behind two layers: the checks below, in this process, and, unless turned off,
+# namespaces of its own that leave it without network and with a filesystem holding only the
+# system's libraries, read-only, and its working directory.
+import ast
+import base64
+import contextlib
+import ctypes
+import io
+import json
+import mimetypes
+import os
+import platform
+import resource
+import sys
+import traceback
+
+# Keep the protocol channel to ourselves: anything else written to fd 1 ends up on stderr.
+_PROTOCOL_IN = sys.stdin
+_PROTOCOL_OUT = os.fdopen(os.dup(1), "w")
+os.dup2(2, 1)
+sys.stdin = io.StringIO()
+
+_CONFIG = json.loads(sys.argv[1])
+_WORK_DIR = os.path.realpath(os.getcwd())
+# Outside of the working directory, only the library directories can be read, so that
+# authorized modules can still be imported
+_LIBRARY_DIRS = tuple(sorted({os.path.realpath(path) for path in sys.path if path}))
+_AUTHORIZED_IMPORTS = tuple(_CONFIG["authorized_imports"])
+
+# Builtins and attributes that would let code reach around the checks below
+_FORBIDDEN_NAMES = frozenset({
+    "__import__", "eval", "exec", "compile", "globals", "locals", "vars",
+    "getattr", "setattr", "delattr", "breakpoint", "input", "help", "exit", "quit",
+})
+_FORBIDDEN_MODULES = frozenset({
+    "os", "sys", "subprocess", "socket", "shutil", "builtins", "importlib",
+    "ctypes", "multiprocessing", "threading", "signal", "resource", "pty",
+})
+_BLOCKED_EVENTS = (
+    "socket.", "subprocess.", "os.system", "os.exec", "os.fork", "os.forkpty",
+    "os.posix_spawn", "os.spawn", "os.kill", "os.killpg", "pty.", "ctypes.",
+    "urllib.Request", "webbrowser.", "sys.addaudithook", "sys.setprofile", "sys.settrace",
+)
+_FILE_EVENTS = ("os.remove", "os.rename", "os.rmdir", "os.mkdir", "os.chmod", "os.chown",
+                "os.truncate", "os.symlink", "os.link", "shutil.")
+# Larger files the code saves are not attached to the observation
+_MAX_ATTACHMENT_SIZE = 10 * 1024 * 1024
+# Reads the system's type maps, which the sandbox would refuse later on
+mimetypes.init()
+
+# System directories the isolated interpreter sees, read-only, besides the library directories
+_SYSTEM_DIRS = ("/usr", "/lib", "/lib64", "/bin")
+_CLONE_NEWNS, _CLONE_NEWUSER, _CLONE_NEWNET = 0x00020000, 0x10000000, 0x40000000
+_MS_RDONLY, _MS_NOSUID, _MS_NODEV, _MS_NOEXEC = 0x1, 0x2, 0x4, 0x8
+_MS_REMOUNT, _MS_BIND, _MS_REC, _MS_PRIVATE, _MS_RELATIME = 0x20, 0x1000, 0x4000, 0x40000, 0x200000
+_MS_NOATIME, _MS_NODIRATIME = 0x400, 0x800
+_MNT_DETACH = 0x2
+_PR_SET_NO_NEW_PRIVS = 38
+_SYS_PIVOT_ROOT = {"x86_64": 155, "aarch64": 41}
+
+
+def _send(message):
+    _PROTOCOL_OUT.write(json.dumps(message, default=repr) + "\n")
+    _PROTOCOL_OUT.flush()
+
+
+def _receive():
+    line = _PROTOCOL_IN.readline()
+    if not line:
+        sys.exit(0)
+    return json.loads(line)
+
+
+def _isolate(root):
+    """Moves this process into user, mount and network namespaces of its own, with `root`, an
+    empty directory, as the root of a filesystem holding the system and library directories,
+    read-only, and the working directory. All capabilities are dropped afterwards."""
+    libc = ctypes.CDLL(None, use_errno=True)
+
+    def check(result, what):
+        if result != 0:
+            errno = ctypes.get_errno()
+            raise OSError(errno, f"{what}: {os.strerror(errno)}")
+
+    def mount(source, target, flags, fstype=None):
+        encode = lambda value: value.encode() if value is not None else None
+        check(libc.mount(encode(source), encode(target), encode(fstype), flags, None), f"mount {target}")
+
+    def bind(directory, read_only):
+        target = root + directory
+        if os.path.islink(directory):
+            os.makedirs(os.path.dirname(target), exist_ok=True)
+            os.symlink(os.readlink(directory), target)
+            return
+        os.makedirs(target, exist_ok=True)
+        mount(directory, target, _MS_BIND | _MS_REC)
+        if read_only:
+            # Flags the source mount has are locked in, and have to be kept
+            flag = os.statvfs(directory).f_flag
+            kept = flag & (_MS_NOEXEC | _MS_NOATIME | _MS_NODIRATIME)
+            kept |= _MS_RELATIME if flag & os.ST_RELATIME else 0
+            mount(None, target, _MS_BIND | _MS_REMOUNT | _MS_RDONLY | _MS_NOSUID | _MS_NODEV | kept)
+
+    uid, gid = os.getuid(), os.getgid()
+    check(libc.unshare(_CLONE_NEWUSER | _CLONE_NEWNS | _CLONE_NEWNET), "unshare")
+    for name, content in (("setgroups", "deny"), ("uid_map", f"0 {uid} 1"), ("gid_map", f"0 {gid} 1")):
+        with open(f"/proc/self/{name}", "w") as file:
+            file.write(content)
+    mount(None, "/", _MS_REC | _MS_PRIVATE)
+    mount("tmpfs", root, _MS_NOSUID | _MS_NODEV, "tmpfs")
+    mounted = []
+    for directory in _SYSTEM_DIRS + _LIBRARY_DIRS:
+        inside = any(directory == done or directory.startswith(done + os.sep) for done in mounted)
+        if os.path.exists(directory) and os.path.isdir(directory) and not inside:
+            bind(directory, read_only=True)
+            mounted.append(os.path.realpath(directory))
+    bind(_WORK_DIR, read_only=False)
+
+    syscall = _SYS_PIVOT_ROOT.get(platform.machine())
+    if syscall is None:
+        raise OSError(f"pivot_root is not known on {platform.machine()}")
+    os.chdir(root)
+    check(libc.syscall(syscall, b".", b"."), "pivot_root")
+    check(libc.umount2(b".", _MNT_DETACH), "umount")
+    mount(None, "/", _MS_REMOUNT | _MS_BIND | _MS_RDONLY | _MS_NOSUID | _MS_NODEV)
+    os.chdir(_WORK_DIR)
+
+    class Header(ctypes.Structure):
+        _fields_ = [("version", ctypes.c_uint32), ("pid", ctypes.c_int)]
+
+    class Data(ctypes.Structure):
+        _fields_ = [("effective", ctypes.c_uint32), ("permitted", ctypes.c_uint32),
+                    ("inheritable", ctypes.c_uint32)]
+
+    check(libc.prctl(_PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0), "prctl")
+    check(libc.capset(ctypes.byref(Header(0x20080522, 0)), (Data * 2)()), "capset")
+
+
+def _inside(path, directories):
+    if not isinstance(path, (str, bytes, os.PathLike)):
+        return True
+    path = os.path.realpath(os.fsdecode(path))
+    return any(path == directory or path.startswith(directory + os.sep) for directory in directories)
+
+
+def _make_audit_hook(work_dir, readable_dirs):
+    # Everything the hook depends on is bound when it is made, so that code changing this
+    # module's globals doesn't change what it allows
+    def audit(event, args, blocked=_BLOCKED_EVENTS, file_events=_FILE_EVENTS, inside=_inside,
+              is_write=_is_write):
+        if event.startswith(blocked):
+            raise PermissionError(f"{event} is not allowed in this sandbox")
+        if event.startswith(file_events) and args and not inside(args[0], work_dir):
+            raise PermissionError(f"{event} is only allowed inside the working directory")
+        if event == "open":
+            if is_write(args[1], args[2]) and not inside(args[0], work_dir):
+                raise PermissionError("Files can only be written inside the working directory")
+            if not inside(args[0], readable_dirs):
+                raise PermissionError("Files can only be read inside the working directory")
+        if event in ("os.listdir", "os.scandir") and args and not inside(args[0], readable_dirs):
+            raise PermissionError("Directories can only be listed inside the working directory")
+
+    return audit
+
+
+def _is_write(mode, flags):
+    if mode is not None:
+        return any(c in mode for c in "wax+")
+    return bool(flags & (os.O_WRONLY | os.O_RDWR | os.O_CREAT | os.O_APPEND))
+
+
+def _is_authorized(module):
+    return any(
+        allowed == "*" or module == allowed or module.startswith(allowed + ".")
+        for allowed in _AUTHORIZED_IMPORTS
+    )
+
+
+def _check_attribute(attribute):
+    if (attribute.startswith("_") or attribute in _FORBIDDEN_MODULES) and not _is_authorized(attribute):
+        raise PermissionError(f"Access to attribute {attribute} is not allowed")
+
+
+def _check(tree):
+    for node in ast.walk(tree):
+        modules = []
+        if isinstance(node, ast.Import):
+            modules = [alias.name for alias in node.names]
+        elif isinstance(node, ast.ImportFrom):
+            if node.level:
+                raise ImportError("Relative imports are not allowed")
+            modules = [node.module]
+        for module in modules:
+            if not _is_authorized(module):
+                raise ImportError(
+                    f"Import of {module} is not allowed. "
+                    f"Authorized imports are: {', '.join(_AUTHORIZED_IMPORTS) or 'none'}"
+                )
+        if isinstance(node, ast.Attribute):
+            _check_attribute(node.attr)
+        # `case C(attribute=value)` reads attributes too
+        if isinstance(node, ast.MatchClass):
+            for attribute in node.kwd_attrs:
+                _check_attribute(attribute)
+        if isinstance(node, ast.Name) and (node.id in _FORBIDDEN_NAMES or node.id.startswith("__")):
+            raise PermissionError(f"Use of {node.id} is not allowed")
+
+
+def _files():
+    files = {}
+    for entry in os.scandir(_WORK_DIR):
+        if entry.is_file():
+            stat = entry.stat()
+            files[entry.path] = (stat.st_mtime_ns, stat.st_size)
+    return files
+
+
+def _attachments(before):
+    """Files saved since `before` was taken with `_files`, as attachments."""
+    attachments = []
+    for path, file in sorted(_files().items()):
+        if before.get(path) == file or file[1] > _MAX_ATTACHMENT_SIZE:
+            continue
+        mime_type = mimetypes.guess_type(path)[0] or "application/octet-stream"
+        with open(path, "rb") as content:
+            data = base64.b64encode(content.read()).decode()
+        attachments.append({
+            "kind": "image" if mime_type.startswith("image/") else "file",
+            "url": f"data:{mime_type};base64,{data}",
+            "mime_type": mime_type,
+        })
+    return attachments
+
+
+# The tools handed to the code are defined in a namespace of their own, holding only what
+# they need, so that they don't lead back to this module's globals and the sandbox state
+_TOOLS_SOURCE = '''
+def send(message):
+    write(dumps(message, default=repr) + "\\n")
+    flush()
+
+
+def receive():
+    line = readline()
+    if not line:
+        raise SystemExit(0)
+    return loads(line)
+
+
+class FinalAnswer(BaseException):
+    def __init__(self, value):
+        self.value = value
+
+
+def make_tool(name, parameters):
+    def tool(*args, **kwargs):
+        arguments = dict(zip(parameters, args))
+        arguments.update(kwargs)
+        send({"type": "tool_call", "name": name, "arguments": arguments})
+        reply = receive()
+        if reply.get("error") is not None:
+            raise RuntimeError(reply["error"])
+        return reply["result"]
+
+    tool.__name__ = name
+    return tool
+
+
+def make_final_answer(name):
+    def final_answer(answer):
+        raise FinalAnswer(answer)
+
+    final_answer.__name__ = name
+    return final_answer
+'''
+
+
+def _tool_namespace():
+    namespace = {
+        "write": _PROTOCOL_OUT.write,
+        "flush": _PROTOCOL_OUT.flush,
+        "readline": _PROTOCOL_IN.readline,
+        "dumps": json.dumps,
+        "loads": json.loads,
+    }
+    exec(compile(_TOOLS_SOURCE, "<tools>", "exec"), namespace)
+    return namespace
+
+
+def _run(code, result):
+    try:
+        tree = ast.parse(code, "<code>")
+        _check(tree)
+    except (SyntaxError, ImportError, PermissionError) as error:
+        result["error"] = "".join(traceback.format_exception_only(type(error), error))
+        return
+    # Like a REPL, report the value of a trailing expression
+    last = None
+    if tree.body and isinstance(tree.body[-1], ast.Expr):
+        last = ast.Expression(tree.body.pop().value)
+    try:
+        exec(compile(tree, "<code>", "exec"), _globals)
+        if last is not None:
+            result["output"] = eval(compile(last, "<code>", "eval"), _globals)
+    except _FinalAnswer as answer:
+        result["output"] = answer.value
+        result["is_final_answer"] = True
+    except (Exception, SystemExit) as error:
+        # Leave this driver's frame out of the traceback
+        result["error"] = "".join(
+            traceback.format_exception(type(error), error, error.__traceback__.tb_next)
+        )
+
+
+try:
+    if _CONFIG.get("isolation_root"):
+        _isolate(_CONFIG["isolation_root"])
+except OSError as error:
+    _send({"type": "failed", "error": f"Could not isolate the interpreter ({error})"})
+    sys.exit(1)
+
+_namespace = _tool_namespace()
+_FinalAnswer = _namespace["FinalAnswer"]
+_tools = {
+    tool["name"]: _namespace["make_final_answer"](tool["name"])
+    if tool["final_answer"]
+    else _namespace["make_tool"](tool["name"], tool["parameters"])
+    for tool in _CONFIG["tools"]
+}
+_globals = {"__name__": "__main__"}
+
+resource.setrlimit(resource.RLIMIT_CPU, (_CONFIG["cpu_time_limit"],) * 2)
+resource.setrlimit(resource.RLIMIT_AS, (_CONFIG["memory_limit"],) * 2)
+sys.addaudithook(_make_audit_hook((_WORK_DIR,), (_WORK_DIR,) + _LIBRARY_DIRS))
+_send({"type": "started"})
+
+while True:
+    request = _receive()
+    logs = io.StringIO()
+    result = {"type": "result", "output": None, "error": None, "is_final_answer": False}
+    # Tools are restored on every run, in case the code shadowed them
+    _globals.update(_tools)
+    files = _files()
+    with contextlib.redirect_stdout(logs), contextlib.redirect_stderr(logs):
+        _run(request["code"], result)
+    result["logs"] = logs.getvalue()
+    result["attachments"] = _attachments(files)
+    _send(result)
@@ -1,6 +1,8 @@
 use std::collections::HashMap;
-use std::process::Command;
 use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
 use tracing::info;
 use std::fmt;
 use crate::observation::Observation;
@@ -8,8 +10,12 @@ use crate::observation::Observation;
 #[derive(Clone, Debug)]
 pub struct Parameter {
     pub name: String,
-    pub dtype: String,
     pub description: String,
+    /// JSON Schema of the value, e.g. `{"type": "integer", "minimum": 1}`. It may use `enum`,
+    /// `default`, `items` for arrays and `properties` for nested objects.
+    pub schema: Value,
+    /// Whether calls have to provide the value.
+    pub required: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -19,9 +25,265 @@ pub struct ActionInput {
     pub dtype: String,
 }
 
+/// Function definition handed to models that support native tool calling.
+#[derive(Clone, Debug)]
+pub struct ToolSchema {
+    pub name: String,
+    pub description: String,
+    /// JSON Schema object describing the arguments.
+    pub parameters: Value,
+}
+
 impl fmt::Display for Parameter {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}: {{ type: {}, description: {} }}", self.name, self.dtype, self.description)
+        write!(f, "{}: {}", self.name, self.property_schema())
+    }
+}
+
+impl Parameter {
+    /// A required parameter whose values follow `schema`.
+    pub fn new(name: impl Into<String>, description: impl Into<String>, schema: Value) -> Self {
+        Self {
+            name: name.into(),
+            description: description.into(),
+            schema,
+            required: true,
+        }
+    }
+
+    pub fn optional(mut self) -> Self {
+        self.required = false;
+        self
+    }
+
+    /// Makes the parameter optional, `default` being used when calls leave it out.
+    pub fn with_default(mut self, default: Value) -> Self {
+        if let Some(schema) = self.schema.as_object_mut() {
+            schema.insert("default".to_string(), default);
+        }
+        self.optional()
+    }
+
+    /// JSON Schema type name of the values.
+    pub fn json_type(&self) -> &str {
+        schema_type(&self.schema)
+    }
+
+    /// Schema of the parameter as a property of the arguments object, with its description.
+    pub fn property_schema(&self) -> Value {
+        let mut schema = self.schema.clone();
+        if let Some(schema) = schema.as_object_mut() {
+            schema.insert("description".to_string(), Value::String(self.description.clone()));
+        }
+        schema
+    }
+}
+
+/// Problems found in the arguments of a tool call.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ArgumentErrors {
+    /// Required parameters that were not given.
+    pub missing: Vec<String>,
+    /// Arguments whose value doesn't fit the schema, with the reason.
+    pub invalid: Vec<(String, String)>,
+    /// Arguments that match no parameter.
+    pub unexpected: Vec<String>,
+    /// Names of the parameters the action takes.
+    pub expected: Vec<String>,
+}
+
+impl ArgumentErrors {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.invalid.is_empty() && self.unexpected.is_empty()
+    }
+}
+
+impl fmt::Display for ArgumentErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut problems = Vec::new();
+        if !self.missing.is_empty() {
+            problems.push(format!("missing required argument(s): {}", self.missing.join(", ")));
+        }
+        for (name, reason) in &self.invalid {
+            problems.push(format!("invalid argument {}: {}", name, reason));
+        }
+        if !self.unexpected.is_empty() {
+            problems.push(format!(
+                "unexpected argument(s): {} (the parameters are: {})",
+                self.unexpected.join(", "),
+                self.expected.join(", ")
+            ));
+        }
+        write!(f, "{}", problems.join("; "))
+    }
+}
+
+impl std::error::Error for ArgumentErrors {}
+
+/// Checks tool call arguments against the parameters, converting values to the declared types
+/// where the intent is clear (e.g. `"5"` for an integer) and filling in defaults.
+pub fn validate_arguments(
+    parameters: &[Parameter],
+    arguments: &HashMap<String, Value>,
+) -> Result<HashMap<String, Value>, ArgumentErrors> {
+    let mut errors = ArgumentErrors {
+        expected: parameters.iter().map(|param| param.name.clone()).collect(),
+        ..Default::default()
+    };
+    let mut validated = HashMap::new();
+    for param in parameters {
+        // An explicit null stands for a value left out
+        match arguments.get(&param.name).filter(|value| !value.is_null()) {
+            Some(value) => match coerce(value, &param.schema) {
+                Ok(value) => {
+                    validated.insert(param.name.clone(), value);
+                }
+                Err(reason) => errors.invalid.push((param.name.clone(), reason)),
+            },
+            None => {
+                if let Some(default) = param.schema.get("default") {
+                    validated.insert(param.name.clone(), default.clone());
+                } else if param.required {
+                    errors.missing.push(param.name.clone());
+                }
+            }
+        }
+    }
+    errors.unexpected = arguments
+        .keys()
+        .filter(|key| !parameters.iter().any(|param| &param.name == *key))
+        .cloned()
+        .collect();
+    errors.unexpected.sort();
+    if errors.is_empty() {
+        Ok(validated)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Converts `value` to fit `schema`, or explains why it doesn't.
+fn coerce(value: &Value, schema: &Value) -> Result<Value, String> {
+    let parse_json = |s: &str| serde_json::from_str::<Value>(s).ok();
+    let coerced = match (schema_type(schema), value) {
+        ("string", Value::String(_)) => value.clone(),
+        ("string", Value::Number(_) | Value::Bool(_)) => Value::String(value.to_string()),
+        ("integer", Value::Number(n)) if n.is_i64() || n.is_u64() => value.clone(),
+        ("integer", Value::Number(n)) if n.as_f64().is_some_and(|f| f.fract() == 0.0) => {
+            json!(n.as_f64().unwrap_or_default() as i64)
+        }
+        ("integer", Value::String(s)) if s.trim().parse::<i64>().is_ok() => json!(s.trim().parse::<i64>().unwrap_or_default()),
+        ("number", Value::Number(_)) => value.clone(),
+        ("number", Value::String(s)) if s.trim().parse::<f64>().is_ok() => json!(s.trim().parse::<f64>().unwrap_or_default()),
+        ("boolean", Value::Bool(_)) => value.clone(),
+        ("boolean", Value::String(s)) if s.eq_ignore_ascii_case("true") => Value::Bool(true),
+        ("boolean", Value::String(s)) if s.eq_ignore_ascii_case("false") => Value::Bool(false),
+        ("array", Value::Array(items)) => {
+            let item_schema = schema.get("items").cloned().unwrap_or(json!({}));
+            let items = items
+                .iter()
+                .enumerate()
+                .map(|(i, item)| coerce(item, &item_schema).map_err(|reason| format!("item {}: {}", i, reason)))
+                .collect::<Result<Vec<_>, _>>()?;
+            Value::Array(items)
+        }
+        ("array", Value::String(s)) => match parse_json(s) {
+            Some(parsed @ Value::Array(_)) => return coerce(&parsed, schema),
+            _ => return Err(format!("expected an array, got {}", value)),
+        },
+        ("object", Value::Object(map)) => {
+            let properties = schema.get("properties").and_then(Value::as_object);
+            let mut object = Map::new();
+            for (key, item) in map {
+                let item = match properties.and_then(|properties| properties.get(key)) {
+                    Some(item_schema) => coerce(item, item_schema).map_err(|reason| format!("field {}: {}", key, reason))?,
+                    None => item.clone(),
+                };
+                object.insert(key.clone(), item);
+            }
+            let required = schema.get("required").and_then(Value::as_array).into_iter().flatten();
+            for key in required.filter_map(Value::as_str) {
+                if !object.contains_key(key) {
+                    return Err(format!("missing field {}", key));
+                }
+            }
+            Value::Object(object)
+        }
+        ("object", Value::String(s)) => match parse_json(s) {
+            Some(parsed @ Value::Object(_)) => return coerce(&parsed, schema),
+            _ => return Err(format!("expected an object, got {}", value)),
+        },
+        ("any", _) => value.clone(),
+        (expected, _) => return Err(format!("expected {} {}, got {}", article(expected), expected, value)),
+    };
+
+    if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+        if !allowed.contains(&coerced) {
+            let allowed: Vec<String> = allowed.iter().map(Value::to_string).collect();
+            return Err(format!("{} is not one of {}", coerced, allowed.join(", ")));
+        }
+    }
+    if let Some(n) = coerced.as_f64() {
+        if let Some(minimum) = schema.get("minimum").and_then(Value::as_f64).filter(|&min| n < min) {
+            return Err(format!("{} is less than the minimum of {}", coerced, minimum));
+        }
+        if let Some(maximum) = schema.get("maximum").and_then(Value::as_f64).filter(|&max| n > max) {
+            return Err(format!("{} is greater than the maximum of {}", coerced, maximum));
+        }
+    }
+    Ok(coerced)
+}
+
+fn article(type_name: &str) -> &'static str {
+    match type_name.chars().next() {
+        Some('a' | 'e' | 'i' | 'o' | 'u') => "an",
+        _ => "a",
+    }
+}
+
+/// The `type` of a JSON Schema, `"any"` when the schema doesn't restrict it.
+pub fn schema_type(schema: &Value) -> &str {
+    schema.get("type").and_then(Value::as_str).unwrap_or("any")
+}
+
+impl ActionBase {
+    /// JSON Schema of the arguments object.
+    pub fn input_schema(&self) -> Value {
+        let properties: Map<String, Value> = self
+            .parameters
+            .iter()
+            .map(|param| (param.name.clone(), param.property_schema()))
+            .collect();
+        let required: Vec<&str> = self
+            .parameters
+            .iter()
+            .filter(|param| param.required)
+            .map(|param| param.name.as_str())
+            .collect();
+        json!({
+            "type": "object",
+            "properties": properties,
+            "required": required,
+        })
+    }
+
+    /// Describes the action to the prompt templates, as `tools` entries.
+    pub fn template_context(&self) -> Value {
+        json!({
+            "name": self.name,
+            "description": self.description,
+            "inputs": self.input_schema()["properties"],
+            "output_type": schema_type(&self.output_schema),
+            "output_schema": self.output_schema,
+        })
+    }
+
+    pub fn tool_schema(&self) -> ToolSchema {
+        ToolSchema {
+            name: self.name.clone(),
+            description: self.description.clone(),
+            parameters: self.input_schema(),
+        }
     }
 }
 
@@ -33,23 +295,85 @@ pub trait Action: Send + Sync {
             .iter()
             .filter_map(|param| {
                 inputs.iter().find(|input| {
-                    param.name == input.key && param.dtype.eq_ignore_ascii_case(&input.dtype)
+                    param.name == input.key && param.json_type().eq_ignore_ascii_case(&input.dtype)
                 })
                     .map(|input| (param.name.clone(), input.clone()))
             })
             .collect()
     }
-    fn as_str(&self) -> String;
+    /// Checks and converts the arguments of a call before it is acted on.
+    fn validate_arguments(&self, arguments: &HashMap<String, Value>) -> Result<HashMap<String, Value>, ArgumentErrors> {
+        validate_arguments(self.get_parameters(), arguments)
+    }
+    fn get_info(&self) -> &ActionBase;
     fn get_parameters(&self) -> &Vec<Parameter>;
+    fn tool_schema(&self) -> ToolSchema {
+        self.get_info().tool_schema()
+    }
+    /// Whether calling this action ends the run, its output being the final answer.
+    fn is_final_answer(&self) -> bool {
+        false
+    }
+    /// Whether this action hands its task to another agent, which the prompts list as a team member.
+    fn is_managed_agent(&self) -> bool {
+        false
+    }
+    /// Whether this action asks the user a question. The agent forwards its `question` input to
+    /// the user of the run and observes the answer, falling back to `act` when no user can answer.
+    fn asks_user(&self) -> bool {
+        false
+    }
     async fn act(&self, inputs: Vec<ActionInput>) -> Observation;
 }
 
+/// Conversion of an action's return value into the observation shown to the model.
+pub trait IntoObservation {
+    fn into_observation(self) -> Observation;
+}
+
+impl IntoObservation for Observation {
+    fn into_observation(self) -> Observation {
+        self
+    }
+}
+
+impl IntoObservation for String {
+    fn into_observation(self) -> Observation {
+        Observation::success(self)
+    }
+}
+
+impl<T: IntoObservation, E: fmt::Display> IntoObservation for Result<T, E> {
+    fn into_observation(self) -> Observation {
+        match self {
+            Ok(value) => value.into_observation(),
+            Err(err) => Observation::error(format!("Error: {}", err)),
+        }
+    }
+}
+
+/// Deserializes the input `key`, taking its value as a string first and as JSON second.
+/// Returns `None` if the input is missing.
+pub fn input_value<T: DeserializeOwned>(
+    inputs: &HashMap<String, ActionInput>,
+    key: &str,
+) -> Result<Option<T>, String> {
+    let Some(input) = inputs.get(key) else {
+        return Ok(None);
+    };
+    serde_json::from_value(Value::String(input.value.clone()))
+        .or_else(|_| serde_json::from_str(&input.value))
+        .map(Some)
+        .map_err(|err| format!("Invalid value for input {}: {}", key, err))
+}
+
 #[derive(Clone, Debug)]
 pub struct ActionBase {
     pub name: String,
     pub description: String,
     pub parameters: Vec<Parameter>,
-    pub output_type: String,
+    /// JSON Schema of the action's output.
+    pub output_schema: Value,
 }
 
 
@@ -57,10 +381,60 @@ pub struct NaverNewsSearchAction {
     pub info: ActionBase,
     pub client_id: String,
     pub client_secret: String,
+    /// Root of the Naver Open API, e.g. `https://openapi.naver.com`.
+    pub base_url: String,
+    client: reqwest::Client,
+}
+
+/// News article found by `NaverNewsSearchAction`.
+#[derive(Debug, Serialize)]
+pub struct NewsArticle {
+    pub title: String,
+    pub description: String,
+    pub link: String,
+    pub original_link: String,
+    pub pub_date: String,
+}
+
+#[derive(Deserialize)]
+struct NaverNewsResponse {
+    items: Vec<NaverNewsItem>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NaverNewsItem {
+    title: String,
+    #[serde(rename = "originallink")]
+    original_link: String,
+    link: String,
+    description: String,
+    pub_date: String,
 }
 
 pub struct DuckDuckGoSearchAction {
     pub info: ActionBase,
+    /// Root of the DuckDuckGo HTML site, e.g. `https://html.duckduckgo.com`.
+    pub base_url: String,
+    /// Number of results returned when the call doesn't say.
+    pub max_results: usize,
+    client: reqwest::Client,
+}
+
+/// Web page found by `DuckDuckGoSearchAction`.
+#[derive(Debug, Serialize)]
+pub struct SearchResult {
+    pub title: String,
+    pub url: String,
+    pub snippet: String,
+}
+
+pub struct FinalAnswerAction {
+    pub info: ActionBase,
+}
+
+pub struct AskUserAction {
+    pub info: ActionBase,
 }
 
 impl NaverNewsSearchAction {
@@ -68,20 +442,91 @@ impl NaverNewsSearchAction {
         Self {
             info: ActionBase {
                 name: "NaverNewsSearchAction".to_string(),
-                description: "Search the web using Naver News".to_string(),
+                description: "Search Korean news articles using Naver News. Returns a JSON list of articles with their title, description, link and publication date.".to_string(),
                 parameters: vec![
-                    Parameter {
-                        name: "query".to_string(),
-                        dtype: "String".to_string(),
-                        description: "Search query".to_string(),
-                    },
+                    Parameter::new("query", "Search query", json!({ "type": "string" })),
+                    Parameter::new(
+                        "display",
+                        "Number of articles to return",
+                        json!({ "type": "integer", "minimum": 1, "maximum": 100 }),
+                    )
+                    .with_default(json!(10)),
+                    Parameter::new(
+                        "start",
+                        "Rank of the first article to return",
+                        json!({ "type": "integer", "minimum": 1, "maximum": 1000 }),
+                    )
+                    .with_default(json!(1)),
+                    Parameter::new(
+                        "sort",
+                        "'sim' to sort by relevance, 'date' to sort by date",
+                        json!({ "type": "string", "enum": ["sim", "date"] }),
+                    )
+                    .with_default(json!("sim")),
                 ],
-                output_type: "String".to_string(),
+                output_schema: json!({
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "title": { "type": "string" },
+                            "description": { "type": "string" },
+                            "link": { "type": "string" },
+                            "original_link": { "type": "string" },
+                            "pub_date": { "type": "string" },
+                        },
+                    },
+                }),
             },
             client_id,
             client_secret,
+            base_url: "https://openapi.naver.com".to_string(),
+            client: reqwest::Client::new(),
         }
     }
+
+    /// Sends requests to `base_url` instead of the Naver Open API.
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into();
+        self
+    }
+
+    pub async fn search(
+        &self,
+        query: &str,
+        display: u32,
+        start: u32,
+        sort: &str,
+    ) -> Result<Vec<NewsArticle>, reqwest::Error> {
+        let url = format!("{}/v1/search/news.json", self.base_url.trim_end_matches('/'));
+        let response: NaverNewsResponse = self
+            .client
+            .get(url)
+            .header("X-Naver-Client-Id", &self.client_id)
+            .header("X-Naver-Client-Secret", &self.client_secret)
+            .query(&[
+                ("query", query),
+                ("display", &display.to_string()),
+                ("start", &start.to_string()),
+                ("sort", sort),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(response
+            .items
+            .into_iter()
+            .map(|item| NewsArticle {
+                title: strip_html(&item.title),
+                description: strip_html(&item.description),
+                link: item.link,
+                original_link: item.original_link,
+                pub_date: item.pub_date,
+            })
+            .collect())
+    }
 }
 
 impl DuckDuckGoSearchAction {
@@ -89,15 +534,87 @@ impl DuckDuckGoSearchAction {
         Self {
             info: ActionBase {
                 name: "DuckDuckGoSearchAction".to_string(),
-                description: "Search the web using DuckDuckGo".to_string(),
+                description: "Search the web using DuckDuckGo. Returns a JSON list of results with their title, url and snippet.".to_string(),
                 parameters: vec![
-                    Parameter {
-                        name: "query".to_string(),
-                        dtype: "String".to_string(),
-                        description: "Search query".to_string(),
+                    Parameter::new("query", "Search query", json!({ "type": "string" })),
+                    max_results_parameter(10),
+                ],
+                output_schema: json!({
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "title": { "type": "string" },
+                            "url": { "type": "string" },
+                            "snippet": { "type": "string" },
+                        },
                     },
+                }),
+            },
+            base_url: "https://html.duckduckgo.com".to_string(),
+            max_results: 10,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Sends requests to `base_url` instead of DuckDuckGo.
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into();
+        self
+    }
+
+    /// Caps the number of results, which are as many when the model doesn't ask for fewer.
+    pub fn with_max_results(mut self, max_results: usize) -> Self {
+        self.max_results = max_results;
+        for parameter in &mut self.info.parameters {
+            if parameter.name == "max_results" {
+                *parameter = max_results_parameter(max_results);
+            }
+        }
+        self
+    }
+
+    pub async fn search(&self, query: &str, max_results: usize) -> Result<Vec<SearchResult>, reqwest::Error> {
+        let url = format!("{}/html/", self.base_url.trim_end_matches('/'));
+        let page = self
+            .client
+            .get(url)
+            .header(reqwest::header::USER_AGENT, "Mozilla/5.0 (compatible; agent-rs)")
+            .query(&[("q", query)])
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        Ok(parse_duckduckgo_results(&page, max_results))
+    }
+}
+
+impl FinalAnswerAction {
+    pub fn new() -> Self {
+        Self {
+            info: ActionBase {
+                name: "final_answer".to_string(),
+                description: "Provides a final answer to the given problem.".to_string(),
+                parameters: vec![
+                    Parameter::new("answer", "The final answer to the problem", json!({ "type": "string" })),
                 ],
-                output_type: "String".to_string(),
+                output_schema: json!({ "type": "string" }),
+            },
+        }
+    }
+}
+
+impl AskUserAction {
+    pub fn new() -> Self {
+        Self {
+            info: ActionBase {
+                name: "ask_user".to_string(),
+                description: "Asks the user a question, e.g. to clarify the task or to confirm a choice, and returns their answer.".to_string(),
+                parameters: vec![
+                    Parameter::new("question", "The question to ask the user", json!({ "type": "string" })),
+                ],
+                output_schema: json!({ "type": "string" }),
             },
         }
     }
@@ -106,8 +623,8 @@ impl DuckDuckGoSearchAction {
 
 #[async_trait]
 impl Action for NaverNewsSearchAction {
-    fn as_str(&self) -> String {
-        format!("- {}: {}\n\tTakes inputs: {:?}\n\tReturns an output of type: {}", self.info.name, self.info.description, self.info.parameters, self.info.output_type)
+    fn get_info(&self) -> &ActionBase {
+        &self.info
     }
 
     fn get_parameters(&self) -> &Vec<Parameter> {
@@ -117,17 +634,35 @@ impl Action for NaverNewsSearchAction {
     async fn act(&self, inputs: Vec<ActionInput>) -> Observation {
         info!("NaverNewsSearchAction.act() called");
         let matched_inputs = self.prepare_inputs(inputs);
-        Observation {
-            result: format!("Matched {} input(s)", matched_inputs.len()),
-        }
+        let Some(query) = matched_inputs.get("query").map(|input| input.value.as_str()) else {
+            return Observation::error("Missing required input: query");
+        };
+        let number = |key: &str, default: u32, max: u32| {
+            input_value::<u32>(&matched_inputs, key)
+                .ok()
+                .flatten()
+                .map_or(default, |n| n.clamp(1, max))
+        };
+        let display = number("display", 10, 100);
+        let start = number("start", 1, 1000);
+        let sort = match matched_inputs.get("sort").map(|input| input.value.as_str()) {
+            Some("date") => "date",
+            _ => "sim",
+        };
+
+        self.search(query, display, start, sort)
+            .await
+            .map(|articles| list_observation(&articles, || format!("No news articles found for '{}'.", query)))
+            .map_err(|err| format!("Naver news search failed: {}", err))
+            .into_observation()
     }
 }
 
 
 #[async_trait]
 impl Action for DuckDuckGoSearchAction {
-    fn as_str(&self) -> String {
-        format!("- {}: {}\n\tTakes inputs: {:?}\n\tReturns an output of type: {}", self.info.name, self.info.description, self.info.parameters, self.info.output_type)
+    fn get_info(&self) -> &ActionBase {
+        &self.info
     }
 
     fn get_parameters(&self) -> &Vec<Parameter> {
@@ -137,14 +672,515 @@ impl Action for DuckDuckGoSearchAction {
     async fn act(&self, inputs: Vec<ActionInput>) -> Observation {
         info!("DuckDuckGoSearchAction.act() called");
         let matched_inputs = self.prepare_inputs(inputs);
-        let query = matched_inputs.get("query").unwrap().value.clone();
-        let output = Command::new("duckduckgo")
-            .arg(format!("--query={}", query))
-            .output()
-            .expect("Failed to execute command");
-        let stdout_str = String::from_utf8_lossy(&output.stdout);
-        Observation {
-            result: stdout_str.to_string(),
+        let Some(query) = matched_inputs.get("query").map(|input| input.value.as_str()) else {
+            return Observation::error("Missing required input: query");
+        };
+        let max_results = input_value::<usize>(&matched_inputs, "max_results")
+            .ok()
+            .flatten()
+            .map_or(self.max_results, |n| n.clamp(1, self.max_results));
+
+        self.search(query, max_results)
+            .await
+            .map(|results| list_observation(&results, || format!("No results found for '{}'.", query)))
+            .map_err(|err| format!("DuckDuckGo search failed: {}", err))
+            .into_observation()
+    }
+}
+
+
+#[async_trait]
+impl Action for FinalAnswerAction {
+    fn get_info(&self) -> &ActionBase {
+        &self.info
+    }
+
+    fn get_parameters(&self) -> &Vec<Parameter> {
+        &self.info.parameters
+    }
+
+    fn is_final_answer(&self) -> bool {
+        true
+    }
+
+    async fn act(&self, inputs: Vec<ActionInput>) -> Observation {
+        info!("FinalAnswerAction.act() called");
+        let matched_inputs = self.prepare_inputs(inputs);
+        Observation::success(
+            matched_inputs
+                .get("answer")
+                .map(|input| input.value.clone())
+                .unwrap_or_default(),
+        )
+    }
+}
+
+#[async_trait]
+impl Action for AskUserAction {
+    fn get_info(&self) -> &ActionBase {
+        &self.info
+    }
+
+    fn get_parameters(&self) -> &Vec<Parameter> {
+        &self.info.parameters
+    }
+
+    fn asks_user(&self) -> bool {
+        true
+    }
+
+    async fn act(&self, _inputs: Vec<ActionInput>) -> Observation {
+        Observation::error("No user is available to answer questions in this run. Carry on without asking.")
+    }
+}
+
+/// Shows `items` as pretty-printed JSON, or the message of `empty` if there are none.
+fn list_observation<T: Serialize>(items: &[T], empty: impl FnOnce() -> String) -> Observation {
+    let data = serde_json::to_value(items).unwrap_or_default();
+    if items.is_empty() {
+        return Observation::success(empty()).with_data(data);
+    }
+    Observation::success(serde_json::to_string_pretty(&data).unwrap_or_default()).with_data(data)
+}
+
+/// The `max_results` parameter of DuckDuckGo searches, capped at `max_results`. Calls that leave
+/// it out get as many results.
+fn max_results_parameter(max_results: usize) -> Parameter {
+    Parameter::new(
+        "max_results",
+        "Maximum number of results to return",
+        json!({ "type": "integer", "minimum": 1, "maximum": max_results }),
+    )
+    .optional()
+}
+
+/// Removes the markup Naver puts in titles and descriptions, e.g. `<b>` around matches.
+fn strip_html(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut in_tag = false;
+    for c in text.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => stripped.push(c),
+            _ => {}
+        }
+    }
+    stripped
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+/// Extracts the results of a DuckDuckGo HTML results page, leaving out ads.
+fn parse_duckduckgo_results(page: &str, max_results: usize) -> Vec<SearchResult> {
+    let mut results = Vec::new();
+    let blocks: Vec<&str> = page.split("class=\"result__a\"").collect();
+    for (before, block) in blocks.iter().zip(blocks.iter().skip(1)) {
+        if results.len() >= max_results {
+            break;
+        }
+        if is_ad(before) {
+            continue;
+        }
+        let href = attribute(block, "href").unwrap_or_default();
+        let Some(title) = element_text(block) else {
+            continue;
+        };
+        let snippet = block
+            .split_once("class=\"result__snippet\"")
+            .and_then(|(_, rest)| element_text(rest))
+            .unwrap_or_default();
+        results.push(SearchResult {
+            title,
+            url: result_url(&href),
+            snippet,
+        });
+    }
+    results
+}
+
+/// Whether the result whose link follows `fragment` is an ad, as told by the classes of the
+/// result's container, e.g. `<div class="result results_links result--ad">`.
+fn is_ad(fragment: &str) -> bool {
+    let Some(start) = fragment.rfind("class=\"result ") else {
+        return false;
+    };
+    let classes = &fragment[start + "class=\"".len()..];
+    let classes = &classes[..classes.find('"').unwrap_or(classes.len())];
+    classes.split_whitespace().any(|class| class == "result--ad")
+}
+
+/// Value of the `name` attribute of the tag `fragment` is in.
+fn attribute(fragment: &str, name: &str) -> Option<String> {
+    let tag = &fragment[..fragment.find('>')?];
+    let start = tag.find(&format!("{}=\"", name))? + name.len() + 2;
+    let end = start + tag[start..].find('"')?;
+    Some(strip_html(&tag[start..end]))
+}
+
+/// Text of the element whose opening tag `fragment` is in.
+fn element_text(fragment: &str) -> Option<String> {
+    let start = fragment.find('>')? + 1;
+    let end = start + fragment[start..].find("</a>")?;
+    Some(strip_html(&fragment[start..end]).trim().to_string())
+}
+
+/// Resolves DuckDuckGo's redirect links (`//duckduckgo.com/l/?uddg=...`) to the target URL.
+fn result_url(href: &str) -> String {
+    let absolute = if href.starts_with("//") { format!("https:{}", href) } else { href.to_string() };
+    reqwest::Url::parse(&absolute)
+        .ok()
+        .and_then(|url| url.query_pairs().find(|(key, _)| key == "uddg").map(|(_, target)| target.into_owned()))
+        .unwrap_or(absolute)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    use axum::extract::Query;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::get;
+    use axum::Router;
+
+    /// Query parameters and headers of the requests a mock server received.
+    type Requests = Arc<Mutex<Vec<(HashMap<String, String>, HeaderMap)>>>;
+
+    /// Serves `router` on a local port and returns its base URL.
+    async fn serve(router: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        format!("http://{}", address)
+    }
+
+    /// A mock server answering `path` with `status` and `body`, recording the requests.
+    async fn mock(path: &str, status: StatusCode, body: &'static str) -> (String, Requests) {
+        let requests = Requests::default();
+        let recorded = requests.clone();
+        let router = Router::new().route(
+            path,
+            get(move |Query(query): Query<HashMap<String, String>>, headers: HeaderMap| async move {
+                recorded.lock().unwrap().push((query, headers));
+                (status, body)
+            }),
+        );
+        (serve(router).await, requests)
+    }
+
+    /// Inputs for `action`, typed like the agent types them.
+    fn inputs(action: &dyn Action, inputs: &[(&str, &str)]) -> Vec<ActionInput> {
+        inputs
+            .iter()
+            .map(|(key, value)| ActionInput {
+                key: key.to_string(),
+                value: value.to_string(),
+                dtype: action
+                    .get_parameters()
+                    .iter()
+                    .find(|param| param.name == *key)
+                    .map_or("string", |param| param.json_type())
+                    .to_string(),
+            })
+            .collect()
+    }
+
+    fn naver(base_url: &str) -> NaverNewsSearchAction {
+        NaverNewsSearchAction::new("client-id".to_string(), "client-secret".to_string())
+            .with_base_url(base_url)
+    }
+
+    #[test]
+    fn coerce_converts_values_whose_intent_is_clear() {
+        let cases = [
+            (json!({ "type": "string" }), json!(5), json!("5")),
+            (json!({ "type": "string" }), json!(true), json!("true")),
+            (json!({ "type": "integer" }), json!(3.0), json!(3)),
+            (json!({ "type": "integer" }), json!(" 42 "), json!(42)),
+            (json!({ "type": "number" }), json!("2.5"), json!(2.5)),
+            (json!({ "type": "boolean" }), json!("TRUE"), json!(true)),
+            (json!({ "type": "boolean" }), json!("false"), json!(false)),
+            (json!({ "type": "array", "items": { "type": "integer" } }), json!("[1, \"2\"]"), json!([1, 2])),
+            (
+                json!({ "type": "object", "properties": { "n": { "type": "integer" } } }),
+                json!("{\"n\": \"7\", \"other\": null}"),
+                json!({ "n": 7, "other": null }),
+            ),
+            (json!({ "description": "anything" }), json!({ "a": [1] }), json!({ "a": [1] })),
+        ];
+        for (schema, value, expected) in cases {
+            assert_eq!(coerce(&value, &schema), Ok(expected), "{} as {}", value, schema);
         }
     }
+
+    #[test]
+    fn coerce_explains_what_does_not_fit() {
+        let cases = [
+            (json!({ "type": "integer" }), json!(2.5), "expected an integer, got 2.5"),
+            (json!({ "type": "integer" }), json!(true), "expected an integer, got true"),
+            (json!({ "type": "boolean" }), json!("yes"), "expected a boolean, got \"yes\""),
+            (json!({ "type": "string" }), json!(null), "expected a string, got null"),
+            (json!({ "type": "array" }), json!("1, 2"), "expected an array, got \"1, 2\""),
+            (json!({ "type": "object" }), json!("[]"), "expected an object, got \"[]\""),
+            (
+                json!({ "type": "array", "items": { "type": "integer" } }),
+                json!([1, "x"]),
+                "item 1: expected an integer, got \"x\"",
+            ),
+            (
+                json!({ "type": "object", "properties": { "n": { "type": "integer" } } }),
+                json!({ "n": "x" }),
+                "field n: expected an integer, got \"x\"",
+            ),
+            (json!({ "type": "object", "required": ["n"] }), json!({}), "missing field n"),
+            (
+                json!({ "type": "string", "enum": ["sim", "date"] }),
+                json!("newest"),
+                "\"newest\" is not one of \"sim\", \"date\"",
+            ),
+            (json!({ "type": "integer", "minimum": 1 }), json!("0"), "0 is less than the minimum of 1"),
+            (json!({ "type": "number", "maximum": 1 }), json!(1.5), "1.5 is greater than the maximum of 1"),
+        ];
+        for (schema, value, expected) in cases {
+            assert_eq!(coerce(&value, &schema), Err(expected.to_string()), "{} as {}", value, schema);
+        }
+    }
+
+    #[test]
+    fn null_arguments_are_left_out() {
+        let parameters = [
+            Parameter::new("query", "Search query", json!({ "type": "string" })),
+            Parameter::new("sort", "Order", json!({ "type": "string" })).with_default(json!("sim")),
+            Parameter::new("limit", "Limit", json!({ "type": "integer" })).optional(),
+        ];
+        let arguments = HashMap::from([
+            ("query".to_string(), json!("rust")),
+            ("sort".to_string(), Value::Null),
+            ("limit".to_string(), Value::Null),
+        ]);
+        let validated = validate_arguments(&parameters, &arguments).unwrap();
+        assert_eq!(
+            validated,
+            HashMap::from([("query".to_string(), json!("rust")), ("sort".to_string(), json!("sim"))])
+        );
+
+        let arguments = HashMap::from([("query".to_string(), Value::Null), ("page".to_string(), json!(2))]);
+        let err = validate_arguments(&parameters, &arguments).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "missing required argument(s): query; unexpected argument(s): page (the parameters are: query, sort, limit)"
+        );
+    }
+
+    /// Converts a temperature to Fahrenheit.
+    ///
+    /// # Arguments
+    /// * `celsius` - Temperature in degrees Celsius
+    /// * `decimals` - Number of decimals to round to
+    #[agent_rs_macros::action(name = "to_fahrenheit")]
+    fn to_fahrenheit(celsius: f64, decimals: Option<usize>) -> String {
+        format!("{:.*}", decimals.unwrap_or(0), celsius * 9.0 / 5.0 + 32.0)
+    }
+
+    #[tokio::test]
+    async fn action_macro_builds_an_action_from_a_function() {
+        let action = ToFahrenheitAction::new();
+        let info = action.get_info();
+        assert_eq!(info.name, "to_fahrenheit");
+        assert_eq!(info.description, "Converts a temperature to Fahrenheit.");
+        assert_eq!(
+            info.input_schema(),
+            json!({
+                "type": "object",
+                "properties": {
+                    "celsius": { "type": "number", "description": "Temperature in degrees Celsius" },
+                    "decimals": {
+                        "type": "integer",
+                        "minimum": 0,
+                        "description": "Number of decimals to round to",
+                    },
+                },
+                "required": ["celsius"],
+            })
+        );
+        assert_eq!(info.output_schema, json!({ "type": "string" }));
+
+        let observation = action.act(inputs(&action, &[("celsius", "100")])).await;
+        assert_eq!(observation.result, "212");
+        let observation = action.act(inputs(&action, &[("celsius", "36.6"), ("decimals", "1")])).await;
+        assert_eq!(observation.result, "97.9");
+
+        let observation = action.act(inputs(&action, &[("decimals", "1")])).await;
+        assert!(observation.is_error());
+        assert_eq!(observation.result, "Missing required input: celsius");
+        let observation = action.act(inputs(&action, &[("celsius", "hot")])).await;
+        assert!(observation.is_error());
+        assert!(observation.result.starts_with("Invalid value for input celsius"), "{}", observation.result);
+    }
+
+    /// A DuckDuckGo results page with an ad, as served by `html.duckduckgo.com`.
+    const DUCKDUCKGO_PAGE: &str = r#"<html><body><div class="serp__results"><div id="links" class="results">
+<div class="result results_links results_links_deep result--ad ">
+  <div class="links_main links_deep result__body">
+    <h2 class="result__title"><a rel="nofollow" class="result__a" href="https://duckduckgo.com/y.js?ad_domain=example.com&amp;ad_provider=bing">Learn <b>Rust</b> Fast - Sponsored</a></h2>
+    <a class="result__snippet" href="https://duckduckgo.com/y.js?ad_domain=example.com">Enroll today.</a>
+  </div>
+</div>
+<div class="result results_links results_links_deep web-result ">
+  <div class="links_main links_deep result__body">
+    <h2 class="result__title"><a rel="nofollow" class="result__a" href="//duckduckgo.com/l/?uddg=https%3A%2F%2Fwww.rust%2Dlang.org%2Flearn%3Fq%3Da%26b%3Dc&amp;rut=0f3a">Learn <b>Rust</b> - Rust Programming Language</a></h2>
+    <a class="result__snippet" href="//duckduckgo.com/l/?uddg=https%3A%2F%2Fwww.rust%2Dlang.org%2Flearn">Get started with <b>Rust</b> &amp; Cargo.</a>
+  </div>
+</div>
+<div class="result results_links results_links_deep web-result ">
+  <div class="links_main links_deep result__body">
+    <h2 class="result__title"><a rel="nofollow" class="result__a" href="https://doc.rust-lang.org/book/">The <b>Rust</b> Programming Language</a></h2>
+    <a class="result__snippet" href="https://doc.rust-lang.org/book/">The book.</a>
+  </div>
+</div>
+<div class="result results_links results_links_deep web-result ">
+  <div class="links_main links_deep result__body">
+    <h2 class="result__title"><a rel="nofollow" class="result__a" href="//duckduckgo.com/l/?uddg=https%3A%2F%2Fdoc.rust%2Dlang.org%2Frust%2Dby%2Dexample%2F&amp;rut=9c1d">Rust by Example</a></h2>
+    <a class="result__snippet" href="//duckduckgo.com/l/?uddg=https%3A%2F%2Fdoc.rust%2Dlang.org%2Frust%2Dby%2Dexample%2F">Learn by examples.</a>
+  </div>
+</div>
+</div></div></body></html>"#;
+
+    #[tokio::test]
+    async fn duckduckgo_search_skips_ads_and_resolves_redirects() {
+        let (base_url, requests) = mock("/html/", StatusCode::OK, DUCKDUCKGO_PAGE).await;
+        let action = DuckDuckGoSearchAction::new().with_base_url(base_url).with_max_results(2);
+
+        // The configured cap applies when the model leaves `max_results` out
+        let arguments = HashMap::from([("query".to_string(), json!("learn rust"))]);
+        let validated = action.validate_arguments(&arguments).unwrap();
+        assert_eq!(validated, arguments);
+        let observation = action.act(inputs(&action, &[("query", "learn rust")])).await;
+
+        assert!(!observation.is_error(), "{}", observation.result);
+        let (query, _) = requests.lock().unwrap().pop().unwrap();
+        assert_eq!(query["q"], "learn rust");
+        assert_eq!(
+            observation.data,
+            Some(json!([
+                {
+                    "title": "Learn Rust - Rust Programming Language",
+                    "url": "https://www.rust-lang.org/learn?q=a&b=c",
+                    "snippet": "Get started with Rust & Cargo.",
+                },
+                {
+                    "title": "The Rust Programming Language",
+                    "url": "https://doc.rust-lang.org/book/",
+                    "snippet": "The book.",
+                },
+            ]))
+        );
+    }
+
+    #[tokio::test]
+    async fn duckduckgo_max_results_cannot_exceed_the_cap() {
+        let (base_url, _) = mock("/html/", StatusCode::OK, DUCKDUCKGO_PAGE).await;
+        let action = DuckDuckGoSearchAction::new().with_base_url(base_url).with_max_results(2);
+        assert_eq!(action.get_parameters()[1].schema["maximum"], 2);
+
+        let arguments = HashMap::from([("query".to_string(), json!("rust")), ("max_results".to_string(), json!(3))]);
+        let err = action.validate_arguments(&arguments).unwrap_err();
+        assert_eq!(err.to_string(), "invalid argument max_results: 3 is greater than the maximum of 2");
+
+        let arguments = HashMap::from([("query".to_string(), json!("rust")), ("max_results".to_string(), json!("1"))]);
+        assert_eq!(action.validate_arguments(&arguments).unwrap()["max_results"], json!(1));
+        let observation = action.act(inputs(&action, &[("query", "rust"), ("max_results", "1")])).await;
+        assert_eq!(observation.data.unwrap().as_array().unwrap().len(), 1);
+        // Should a call get past validation, it is still capped
+        let observation = action.act(inputs(&action, &[("query", "rust"), ("max_results", "50")])).await;
+        assert_eq!(observation.data.unwrap().as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn duckduckgo_results_are_capped() {
+        let urls = |max_results| {
+            parse_duckduckgo_results(DUCKDUCKGO_PAGE, max_results)
+                .into_iter()
+                .map(|result| result.url)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(urls(1), ["https://www.rust-lang.org/learn?q=a&b=c"]);
+        assert_eq!(
+            urls(10),
+            [
+                "https://www.rust-lang.org/learn?q=a&b=c",
+                "https://doc.rust-lang.org/book/",
+                "https://doc.rust-lang.org/rust-by-example/",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn naver_search_sends_credentials_and_parameters() {
+        let body = r#"{"items": [{
+            "title": "<b>Rust</b> 1.80 &quot;released&quot;",
+            "originallink": "https://example.com/rust",
+            "link": "https://n.news.naver.com/rust",
+            "description": "Ships <b>LazyCell</b> &amp; LazyLock",
+            "pubDate": "Thu, 25 Jul 2024 09:00:00 +0900"
+        }]}"#;
+        let (base_url, requests) = mock("/v1/search/news.json", StatusCode::OK, body).await;
+
+        let action = naver(&base_url);
+        let inputs = inputs(&action, &[("query", "러스트"), ("display", "5"), ("start", "11"), ("sort", "date")]);
+        let observation = action.act(inputs).await;
+
+        assert!(!observation.is_error(), "{}", observation.result);
+        let (query, headers) = requests.lock().unwrap().pop().unwrap();
+        assert_eq!(headers["X-Naver-Client-Id"], "client-id");
+        assert_eq!(headers["X-Naver-Client-Secret"], "client-secret");
+        let expected = [("query", "러스트"), ("display", "5"), ("start", "11"), ("sort", "date")];
+        assert_eq!(query, expected.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect());
+        assert_eq!(
+            observation.data,
+            Some(json!([{
+                "title": "Rust 1.80 \"released\"",
+                "description": "Ships LazyCell & LazyLock",
+                "link": "https://n.news.naver.com/rust",
+                "original_link": "https://example.com/rust",
+                "pub_date": "Thu, 25 Jul 2024 09:00:00 +0900",
+            }]))
+        );
+    }
+
+    #[tokio::test]
+    async fn naver_search_defaults_and_clamps_parameters() {
+        let (base_url, requests) = mock("/v1/search/news.json", StatusCode::OK, r#"{"items": []}"#).await;
+
+        let action = naver(&base_url);
+        let inputs = inputs(&action, &[("query", "rust"), ("display", "500"), ("sort", "newest")]);
+        let observation = action.act(inputs).await;
+
+        let (query, _) = requests.lock().unwrap().pop().unwrap();
+        assert_eq!(query["display"], "100");
+        assert_eq!(query["start"], "1");
+        assert_eq!(query["sort"], "sim");
+        assert!(!observation.is_error());
+        assert_eq!(observation.result, "No news articles found for 'rust'.");
+        assert_eq!(observation.data, Some(json!([])));
+    }
+
+    #[tokio::test]
+    async fn naver_search_reports_http_errors() {
+        let body = r#"{"errorMessage": "Authentication failed", "errorCode": "024"}"#;
+        let (base_url, _) = mock("/v1/search/news.json", StatusCode::UNAUTHORIZED, body).await;
+
+        let action = naver(&base_url);
+        let observation = action.act(inputs(&action, &[("query", "rust")])).await;
+
+        assert!(observation.is_error());
+        assert!(observation.result.starts_with("Error: Naver news search failed"), "{}", observation.result);
+        assert!(observation.result.contains("401"), "{}", observation.result);
+    }
 }
@@ -1,48 +1,170 @@
 use std::sync::Arc;
-use crate::actions::Action;
-use crate::models::Model;
-use crate::prompts::{load_config, Prompt};
-use async_stream::stream;
+use crate::actions::{Action, ActionBase, ActionInput, FinalAnswerAction, Parameter, ToolSchema};
+use crate::events::{AgentEvent, CancelReason};
+use crate::models::{ChatMessage, Model, ModelDelta, ModelError, ModelResponse};
+use crate::observation::Observation;
+use crate::prompts::{load_config, populate_template, Prompt};
 use async_trait::async_trait;
 use futures::stream::Stream;
 use futures::stream::StreamExt;
+use serde::Serialize;
+use serde_json::{json, Value};
 use std::collections::HashMap;
 use std::pin::Pin;
-use std::time::Instant;
-use tracing::info;
-use crate::memory::{AgentMemory, SystemPromptStep};
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tracing::{error, info};
+use crate::memory::{
+    ActionStep, AgentMemory, FinalAnswerStep, PlanningStep, Step, TaskStep, Timing, TokenUsage,
+    ToolCall,
+};
 use crate::memory::AgentMemoryBase;
-use tokio::sync::Mutex;
-/// Represents either a streaming or text result from planning.
-pub enum PlanOutput {
-    Stream(Pin<Box<dyn Stream<Item = String> + Send>>),
-    Text(String),
+use crate::executor::{ExecutionEvent, ExecutionOutput, PythonExecutor, PythonExecutorConfig};
+use crate::sessions::SessionStore;
+use tokio::sync::OwnedMutexGuard;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_util::sync::{CancellationToken, DropGuard};
+
+/// Output of a run: its events, ending with the final answer or the error that aborted the run.
+pub type AgentStream = Pin<Box<dyn Stream<Item = AgentEvent> + Send + 'static>>;
+
+/// Waits for the first event of a run, so that a run that fails before producing any output can
+/// be reported with a proper status code. Returns the events, the first one included, or the error.
+pub async fn started(mut events: AgentStream) -> Result<AgentStream, ModelError> {
+    match events.next().await {
+        Some(AgentEvent::Error { error }) => Err(error),
+        first => Ok(Box::pin(futures::stream::iter(first).chain(events))),
+    }
+}
+
+/// Token consumption of a session, for cost accounting.
+#[derive(Serialize)]
+pub struct SessionUsage {
+    /// Tokens used by the latest run of the session.
+    pub last_run: TokenUsage,
+    /// Tokens used since the session's memory was last reset.
+    pub session: TokenUsage,
 }
 
 #[async_trait]
 pub trait AgentBase {
-    async fn run(self: Arc<Self>, input: String, reset:bool) -> Pin<Box<dyn Stream<Item = String> + Send + 'static>>;
+    /// Runs `input`, along with `images`, in the session's memory. `answers` carries the user's
+    /// answers to the questions the run asks, for runs with a user at hand.
+    async fn run(
+        self: Arc<Self>,
+        session_id: String,
+        input: String,
+        images: Vec<String>,
+        reset: bool,
+        answers: Option<UnboundedReceiver<String>>,
+    ) -> AgentStream;
+    /// Runs `task` in `memory` until it is answered, fails, or `cancel` is triggered. Dropping
+    /// the returned stream cancels the run too.
     async fn _run_stream(
         self: Arc<Self>,
+        memory: OwnedMutexGuard<AgentMemory>,
         task: String,
         max_steps: usize,
         images: Vec<String>,
-    ) -> Pin<Box<dyn Stream<Item = String> + Send + 'static>>;
-    async fn step(&self, state: &str) -> String;
-    async fn plan(&self, state: &str, is_initial: bool) -> PlanOutput;
+        answers: Option<UnboundedReceiver<String>>,
+        cancel: CancellationToken,
+    ) -> AgentStream;
+    /// Runs one action step and records it in `memory`. Returns the final answer, if one was given.
+    async fn step(
+        &self,
+        memory: &mut AgentMemory,
+        step_number: usize,
+        run: &mut RunState,
+        output: &UnboundedSender<AgentEvent>,
+    ) -> Result<Option<Value>, ModelError>;
+    /// Writes a new plan for `task` and records it in `memory`.
+    async fn plan(
+        &self,
+        memory: &mut AgentMemory,
+        task: &str,
+        is_initial: bool,
+        remaining_steps: usize,
+        run: &RunState,
+        output: &UnboundedSender<AgentEvent>,
+    ) -> Result<(), ModelError>;
+    async fn session_usage(&self, session_id: &str) -> Option<SessionUsage>;
+    /// Cancels the run in progress in `session_id`. Returns whether there was one.
+    async fn cancel(&self, session_id: &str) -> bool;
+    /// Starts `session_id` from an earlier conversation, unless the session exists.
+    /// Returns whether it did.
+    async fn restore(&self, session_id: &str, steps: Vec<Step>) -> bool;
+    /// Forgets `session_id`: its memory, and its latest run.
+    async fn forget(&self, session_id: &str);
+    /// Runs `task` to completion in a fresh memory.
+    async fn solve(self: Arc<Self>, task: String) -> Solution;
+}
+
+/// Outcome of a task run to completion by `AgentBase::solve`.
+pub struct Solution {
+    /// The final answer, if one was given, or the model error that aborted the run.
+    pub final_answer: Result<Option<String>, ModelError>,
+    /// Tokens used by the run, whether it succeeded or not.
+    pub token_usage: TokenUsage,
+}
+
+/// Events of a run, which is cancelled when they are dropped, e.g. when the client goes away.
+struct RunEvents {
+    events: UnboundedReceiverStream<AgentEvent>,
+    _cancel_on_drop: DropGuard,
+}
+
+impl Stream for RunEvents {
+    type Item = AgentEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<AgentEvent>> {
+        Pin::new(&mut self.events).poll_next(cx)
+    }
+}
+
+/// How a run ended.
+enum RunEnd {
+    /// With a final answer or an error.
+    Finished,
+    Cancelled(CancelReason),
+}
+
+/// What a run carries from one step to the next, besides its memory.
+#[derive(Default)]
+pub struct RunState {
+    /// Python interpreter of code agents: it is started on first use and kept for the rest of
+    /// the run, so that variables persist between steps.
+    pub executor: Option<PythonExecutor>,
+    /// The user's answers to the questions the run asks, in order.
+    pub answers: Option<UnboundedReceiver<String>>,
 }
 
 pub struct Agent<M: Model> {
     model: M,
     max_steps: usize,
     prompt: Prompt,
-    agent_memory: Mutex<AgentMemory>,
+    sessions: SessionStore,
     available_actions: Vec<Box<dyn Action>>,
     stream_outputs: bool,
-    interrupt_switch: bool,
+    /// Cancellation of the run in progress in each session, removed once the run is over.
+    runs: Mutex<HashMap<String, CancellationToken>>,
+    /// Time after which runs are cancelled.
+    run_timeout: Option<Duration>,
     planning_interval: Option<usize>,
+    custom_instructions: Option<String>,
+    /// Set for code agents, which act by writing Python code instead of calling tools.
+    code_execution: Option<PythonExecutorConfig>,
 }
 
+/// Delimiters of the code blocks written by code agents.
+const CODE_BLOCK_OPENING_TAG: &str = "<code>";
+const CODE_BLOCK_CLOSING_TAG: &str = "</code>";
+/// Name under which code agents report their code blocks as tool calls.
+const PYTHON_INTERPRETER: &str = "python_interpreter";
+/// Observations longer than this many characters are truncated before reaching the model.
+const MAX_OBSERVATION_LENGTH: usize = 20_000;
+
 impl<M: Model> Agent<M> {
     pub fn new(
         model: M,
@@ -51,170 +173,1358 @@ impl<M: Model> Agent<M> {
         stream_outputs: bool,
     ) -> Self {
         let prompt = load_config("data/toolcalling_agent.yaml");
-        let agent_memory = Mutex::new(AgentMemory{
-            system_prompt: SystemPromptStep{system_prompt: prompt.system_prompt.clone()},
-            steps: vec![],
-        });
-        Self {
+        Self::build(model, max_steps, available_actions, stream_outputs, prompt, None)
+    }
+
+    /// Creates an agent that acts by writing Python code, run by an interpreter configured
+    /// by `executor` in which the actions are exposed as functions.
+    pub fn new_code_agent(
+        model: M,
+        max_steps: usize,
+        available_actions: Vec<Box<dyn Action>>,
+        stream_outputs: bool,
+        executor: PythonExecutorConfig,
+    ) -> Self {
+        let prompt = load_config("data/code_agent.yaml");
+        Self::build(model, max_steps, available_actions, stream_outputs, prompt, Some(executor))
+    }
+
+    fn build(
+        model: M,
+        max_steps: usize,
+        available_actions: Vec<Box<dyn Action>>,
+        stream_outputs: bool,
+        prompt: Prompt,
+        code_execution: Option<PythonExecutorConfig>,
+    ) -> Self {
+        let mut available_actions = available_actions;
+        if !available_actions.iter().any(|a| a.is_final_answer()) {
+            available_actions.push(Box::new(FinalAnswerAction::new()));
+        }
+        let mut agent = Self {
             model,
             max_steps,
             prompt,
-            agent_memory,
+            sessions: SessionStore::new(String::new()),
             available_actions,
             stream_outputs,
-            interrupt_switch: false,
+            runs: Mutex::new(HashMap::new()),
+            run_timeout: None,
             planning_interval: None, // Default to None, can be set later
+            custom_instructions: None,
+            code_execution,
+        };
+        agent.check_templates();
+        agent.refresh_system_prompt();
+        agent
+    }
+
+    /// Appends `custom_instructions` to the system prompt.
+    pub fn with_custom_instructions(mut self, custom_instructions: String) -> Self {
+        self.custom_instructions = Some(custom_instructions);
+        self.refresh_system_prompt();
+        self
+    }
+
+    /// Adds a team member the agent can delegate tasks to.
+    pub fn with_managed_agent(mut self, managed_agent: ManagedAgent) -> Self {
+        self.available_actions.push(Box::new(managed_agent));
+        self.refresh_system_prompt();
+        self
+    }
+
+    /// Renders the system prompt for the sessions created from now on.
+    fn refresh_system_prompt(&mut self) {
+        let system_prompt = self.render(&self.prompt.system_prompt, json!({}), false);
+        self.sessions.set_system_prompt(system_prompt);
+    }
+
+    /// The actions offered to a run; actions that ask the user are only offered to runs that
+    /// have one.
+    fn actions(&self, with_user: bool) -> impl Iterator<Item = &dyn Action> {
+        self.available_actions
+            .iter()
+            .map(|a| a.as_ref())
+            .filter(move |a| with_user || !a.asks_user())
+    }
+
+    /// Renders a prompt template with the tools offered to a run, with a user or not, and
+    /// `variables`. Panics on template errors, which `check_templates` reports when the agent
+    /// is built.
+    fn render(&self, template: &str, variables: Value, with_user: bool) -> String {
+        let (managed_agents, tools): (Vec<_>, Vec<_>) =
+            self.actions(with_user).partition(|a| a.is_managed_agent());
+        let describe = |actions: Vec<&dyn Action>| {
+            actions
+                .into_iter()
+                .map(|a| (a.get_info().name.clone(), a.get_info().template_context()))
+                .collect::<serde_json::Map<String, Value>>()
+        };
+        let mut context = json!({
+            "tools": describe(tools),
+            "managed_agents": describe(managed_agents),
+            "custom_instructions": self.custom_instructions,
+            "code_block_opening_tag": CODE_BLOCK_OPENING_TAG,
+            "code_block_closing_tag": CODE_BLOCK_CLOSING_TAG,
+            "authorized_imports": self
+                .code_execution
+                .as_ref()
+                .map(|config| config.authorized_imports.join(", "))
+                .unwrap_or_default(),
+        });
+        if let (Some(context), Value::Object(variables)) = (context.as_object_mut(), variables) {
+            context.extend(variables);
+        }
+        populate_template(template, &context)
+            .unwrap_or_else(|err| panic!("Failed to render prompt template: {:#}", err))
+    }
+
+    /// Renders every prompt template once, so that broken templates fail at startup
+    /// rather than in the middle of a run.
+    fn check_templates(&self) {
+        let variables = json!({
+            "task": "",
+            "remaining_steps": self.max_steps,
+            "name": "",
+            "final_answer": "",
+        });
+        let prompt = &self.prompt;
+        for template in [
+            &prompt.system_prompt,
+            &prompt.planning.initial_plan,
+            &prompt.planning.update_plan_pre_messages,
+            &prompt.planning.update_plan_post_messages,
+            &prompt.managed_agent.task,
+            &prompt.managed_agent.report,
+            &prompt.final_answer.pre_messages,
+            &prompt.final_answer.post_messages,
+        ] {
+            self.render(template, variables.clone(), true);
+        }
+    }
+
+    /// Forgets sessions unused for `idle_timeout`, and keeps at most `max_sessions` of them;
+    /// `None` lifts the limit.
+    pub fn with_session_limits(mut self, idle_timeout: Option<Duration>, max_sessions: Option<usize>) -> Self {
+        self.sessions = self.sessions.with_limits(idle_timeout, max_sessions);
+        self
+    }
+
+    /// Sets how often the agent plans: `None` never plans, `Some(0)` only writes an initial plan,
+    /// and `Some(n)` writes an initial plan and updates it every `n` steps.
+    pub fn with_planning_interval(mut self, planning_interval: Option<usize>) -> Self {
+        self.planning_interval = planning_interval;
+        self
+    }
+
+    /// Cancels runs that take longer than `run_timeout`; `None` lets them run to the end.
+    pub fn with_run_timeout(mut self, run_timeout: Option<Duration>) -> Self {
+        self.run_timeout = run_timeout;
+        self
+    }
+
+    /// Resolves when the run has to stop, telling why.
+    async fn stopped(&self, cancel: &CancellationToken) -> CancelReason {
+        match self.run_timeout {
+            Some(timeout) => tokio::select! {
+                _ = cancel.cancelled() => CancelReason::Cancelled,
+                _ = tokio::time::sleep(timeout) => CancelReason::TimedOut,
+            },
+            None => {
+                cancel.cancelled().await;
+                CancelReason::Cancelled
+            }
         }
     }
+
+    fn should_plan(&self, step_number: usize) -> bool {
+        match self.planning_interval {
+            None => false,
+            Some(0) => step_number == 1,
+            Some(interval) => (step_number - 1).is_multiple_of(interval),
+        }
+    }
+
+    fn find_action(&self, name: &str, with_user: bool) -> Option<&dyn Action> {
+        self.actions(with_user).find(|a| a.get_info().name == name)
+    }
+
+    /// Runs the tool calls of a step, preferring native tool calls and falling back to an
+    /// `Action:` blob in the model output.
+    async fn call_tools(
+        &self,
+        action_step: &mut ActionStep,
+        mut tool_calls: Vec<ToolCall>,
+        answers: &mut Option<UnboundedReceiver<String>>,
+        output: &UnboundedSender<AgentEvent>,
+    ) {
+        let step_number = action_step.step_number;
+        let with_user = answers.is_some();
+        if tool_calls.is_empty() {
+            let model_output = action_step.model_output.as_deref().unwrap_or_default();
+            let Some((name, raw_arguments)) = parse_tool_call(model_output) else {
+                action_step.error = Some(
+                    "Could not find a tool call in the model output. Answer with an 'Action:' blob containing \"name\" and \"arguments\".".to_string(),
+                );
+                return;
+            };
+            let parameters = self
+                .find_action(&name, with_user)
+                .map_or(&[][..], |action| action.get_parameters());
+            tool_calls.push(ToolCall {
+                id: format!("call_{}", step_number),
+                arguments: to_arguments(raw_arguments, parameters),
+                arguments_error: None,
+                name,
+            });
+        }
+
+        let mut observations = Vec::new();
+        let mut errors = Vec::new();
+        let mut images = Vec::new();
+        let mut structured_observations = Vec::new();
+        for call in &tool_calls {
+            let action = match (self.find_action(&call.name, with_user), &call.arguments_error) {
+                (None, _) => Err(self.unknown_tool_error(&call.name, with_user)),
+                (Some(_), Some(err)) => Err(format!("Invalid call to tool {}: {}.", call.name, err)),
+                (Some(action), None) => match action.validate_arguments(&call.arguments) {
+                    Ok(arguments) => Ok((action, arguments)),
+                    Err(err) => Err(format!("Invalid call to tool {}: {}.", call.name, err)),
+                },
+            };
+            let (action, arguments) = match action {
+                Ok(action) => action,
+                Err(err) => {
+                    errors.push(err.clone());
+                    let mut observation = Observation::error(err);
+                    observation.tool_call_id = Some(call.id.clone());
+                    structured_observations.push(observation);
+                    continue;
+                }
+            };
+            info!("Step {}: calling {}", step_number, call.name);
+            if !action.is_final_answer() {
+                let _ = output.send(AgentEvent::ToolCallStarted {
+                    step_number,
+                    id: call.id.clone(),
+                    name: call.name.clone(),
+                    arguments: call.arguments.clone(),
+                });
+            }
+            let mut timing = Timing::start();
+            let mut observation = self
+                .call_action(action, step_number, &call.id, &arguments, answers, output)
+                .await;
+            timing.finish();
+            observation.timing = Some(timing);
+            observation.tool_call_id = Some(call.id.clone());
+            add_token_usage(action_step, observation.token_usage);
+            if action.is_final_answer() && !observation.is_error() {
+                action_step.action_output = Some(Value::String(observation.result.clone()));
+                action_step.is_final_answer = true;
+            } else {
+                observation.truncate(MAX_OBSERVATION_LENGTH);
+                if observation.is_error() {
+                    errors.push(observation.result.clone());
+                } else {
+                    observations.push(observation.result.clone());
+                }
+                images.extend(observation.images());
+                let _ = output.send(AgentEvent::ToolResult {
+                    step_number,
+                    id: call.id.clone(),
+                    name: call.name.clone(),
+                    observation: observation.clone(),
+                });
+            }
+            structured_observations.push(observation);
+        }
+        action_step.tool_calls = Some(tool_calls);
+        if !observations.is_empty() {
+            action_step.observations = Some(observations.join("\n"));
+        }
+        if !images.is_empty() {
+            action_step.observations_images = Some(images);
+        }
+        if !structured_observations.is_empty() {
+            action_step.structured_observations = Some(structured_observations);
+        }
+        if !errors.is_empty() {
+            action_step.error = Some(errors.join("\n"));
+        }
+    }
+
+    /// Runs the code block of a step in the run's Python interpreter, starting it if needed.
+    async fn run_code(
+        &self,
+        action_step: &mut ActionStep,
+        run: &mut RunState,
+        config: &PythonExecutorConfig,
+        output: &UnboundedSender<AgentEvent>,
+    ) {
+        let model_output = action_step.model_output.as_deref().unwrap_or_default();
+        let Some(code) = parse_code_blobs(model_output) else {
+            action_step.error = Some(format!(
+                "Could not find a code block in the model output. Write your code between '{}' and '{}'.",
+                CODE_BLOCK_OPENING_TAG, CODE_BLOCK_CLOSING_TAG
+            ));
+            return;
+        };
+        action_step.code_action = Some(code.clone());
+
+        let interpreter = match &mut run.executor {
+            Some(interpreter) => interpreter,
+            None => match PythonExecutor::spawn(config, self.actions(run.answers.is_some())).await {
+                Ok(interpreter) => run.executor.insert(interpreter),
+                Err(err) => {
+                    action_step.error = Some(format!("Failed to start the Python interpreter: {}", err));
+                    return;
+                }
+            },
+        };
+        let step_number = action_step.step_number;
+        let call = ToolCall {
+            id: format!("call_{}", step_number),
+            name: PYTHON_INTERPRETER.to_string(),
+            arguments: HashMap::from([("code".to_string(), Value::String(code.clone()))]),
+            arguments_error: None,
+        };
+        info!("Step {}: executing code", step_number);
+        let _ = output.send(AgentEvent::ToolCallStarted {
+            step_number,
+            id: call.id.clone(),
+            name: call.name.clone(),
+            arguments: call.arguments.clone(),
+        });
+        let result = match self
+            .execute_code(interpreter, &code, action_step, &mut run.answers, output)
+            .await
+        {
+            Ok(result) => result,
+            Err(err) => {
+                // The interpreter is in an unknown state: start a fresh one next time
+                run.executor = None;
+                action_step.error = Some(format!("Code execution failed: {}", err));
+                return;
+            }
+        };
+
+        let mut observation = result.observation();
+        observation.truncate(MAX_OBSERVATION_LENGTH);
+        if !observation.result.is_empty() {
+            action_step.observations = Some(observation.result.clone());
+        }
+        let images = observation.images();
+        if !images.is_empty() {
+            action_step.observations_images = Some(images);
+        }
+        let _ = output.send(AgentEvent::ToolResult {
+            step_number,
+            id: call.id,
+            name: call.name,
+            observation: observation.clone(),
+        });
+        action_step.structured_observations = Some(vec![observation]);
+        action_step.error = result.error;
+        if result.is_final_answer {
+            action_step.action_output = result.output;
+            action_step.is_final_answer = true;
+        }
+    }
+
+    /// Runs `code` to completion, serving the tool calls it makes.
+    async fn execute_code(
+        &self,
+        interpreter: &mut PythonExecutor,
+        code: &str,
+        action_step: &mut ActionStep,
+        answers: &mut Option<UnboundedReceiver<String>>,
+        output: &UnboundedSender<AgentEvent>,
+    ) -> std::io::Result<ExecutionOutput> {
+        let step_number = action_step.step_number;
+        let mut event = interpreter.execute(code).await?;
+        let mut call_number = 0;
+        loop {
+            let (name, arguments) = match event {
+                ExecutionEvent::Finished(result) => return Ok(result),
+                ExecutionEvent::ToolCall { name, arguments } => (name, arguments),
+            };
+            let result = match self.find_action(&name, answers.is_some()) {
+                Some(action) => match action.validate_arguments(&arguments) {
+                    Ok(arguments) => {
+                        info!("Code called {}", name);
+                        call_number += 1;
+                        let id = format!("call_{}_{}", step_number, call_number);
+                        let observation = self
+                            .call_action(action, step_number, &id, &arguments, answers, output)
+                            .await;
+                        add_token_usage(action_step, observation.token_usage);
+                        if observation.is_error() {
+                            Err(observation.result)
+                        } else {
+                            Ok(observation.result)
+                        }
+                    }
+                    Err(err) => Err(format!("Invalid call to tool {}: {}.", name, err)),
+                },
+                None => Err(self.unknown_tool_error(&name, answers.is_some())),
+            };
+            event = interpreter.send_tool_result(result).await?;
+        }
+    }
+
+    /// Calls `action` with validated `arguments`. Questions to the user are forwarded to the
+    /// user of the run, if it has one, and answered with what they reply.
+    async fn call_action(
+        &self,
+        action: &dyn Action,
+        step_number: usize,
+        id: &str,
+        arguments: &HashMap<String, Value>,
+        answers: &mut Option<UnboundedReceiver<String>>,
+        output: &UnboundedSender<AgentEvent>,
+    ) -> Observation {
+        if let (true, Some(answers)) = (action.asks_user(), answers) {
+            let question = match arguments.get("question") {
+                Some(Value::String(question)) => question.clone(),
+                Some(other) => other.to_string(),
+                None => String::new(),
+            };
+            let _ = output.send(AgentEvent::UserQuestion {
+                step_number,
+                id: id.to_string(),
+                question,
+            });
+            return match answers.recv().await {
+                Some(answer) => Observation::success(answer),
+                None => Observation::error("The user left without answering."),
+            };
+        }
+        action.act(to_action_inputs(arguments, action.get_parameters())).await
+    }
+
+    /// Asks the model for an answer to `task` based on the memory of a run that ran out of steps,
+    /// recording the attempt as a final action step. The answer itself is left to the
+    /// `FinalAnswerStep` that follows, so that it is replayed once.
+    async fn provide_final_answer(
+        &self,
+        memory: &mut AgentMemory,
+        task: &str,
+        step_number: usize,
+        output: &UnboundedSender<AgentEvent>,
+    ) -> Result<String, ModelError> {
+        let variables = json!({ "task": task });
+        let input_messages = replay_history(
+            memory,
+            self.render(&self.prompt.final_answer.pre_messages, variables.clone(), false),
+            self.render(&self.prompt.final_answer.post_messages, variables, false),
+        );
+
+        let mut action_step = ActionStep {
+            step_number,
+            timing: Timing::start(),
+            tool_calls: None,
+            error: None,
+            model_output_message: None,
+            model_output: None,
+            code_action: None,
+            observations: None,
+            observations_images: None,
+            structured_observations: None,
+            action_output: None,
+            token_usage: None,
+            is_final_answer: false,
+        };
+        let result = self.generate(input_messages, vec![], output, text_delta).await;
+        action_step.timing.finish();
+        match &result {
+            Ok(response) => {
+                action_step.token_usage = response.token_usage;
+                action_step.model_output_message = Some(ChatMessage::assistant(response.content.clone()));
+                action_step.action_output = Some(Value::String(response.content.clone()));
+                action_step.is_final_answer = true;
+            }
+            Err(err) => action_step.error = Some(err.to_string()),
+        }
+        let _ = output.send(AgentEvent::StepFinished {
+            step_number,
+            timing: action_step.timing,
+            usage: action_step.token_usage,
+            error: action_step.error.clone(),
+        });
+        memory.steps.push(Step::Action(action_step));
+        result.map(|response| response.content)
+    }
+
+    fn unknown_tool_error(&self, name: &str, with_user: bool) -> String {
+        let available = self
+            .actions(with_user)
+            .map(|a| a.get_info().name.clone())
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!("Unknown tool {}, should be one of: {}.", name, available)
+    }
+
+    /// Sends `messages` to the model, forwarding generated text to `output` as it arrives,
+    /// wrapped by `as_event`.
+    async fn generate(
+        &self,
+        messages: Vec<ChatMessage>,
+        tools: Vec<ToolSchema>,
+        output: &UnboundedSender<AgentEvent>,
+        as_event: fn(String) -> AgentEvent,
+    ) -> Result<ModelResponse, ModelError> {
+        if !self.stream_outputs {
+            let response = self.model.async_generate(messages, tools).await?;
+            let _ = output.send(as_event(response.content.clone()));
+            return Ok(response);
+        }
+        let mut response = ModelResponse::default();
+        let mut gen_stream = self.model.async_generate_stream(messages, tools).await?;
+        while let Some(delta) = gen_stream.next().await {
+            match delta? {
+                ModelDelta::Text(chunk) => {
+                    response.content.push_str(&chunk);
+                    let _ = output.send(as_event(chunk));
+                }
+                ModelDelta::ToolCalls(calls) => response.tool_calls.extend(calls),
+                ModelDelta::Usage(usage) => response.token_usage = Some(usage),
+            }
+        }
+        Ok(response)
+    }
 }
 
 #[async_trait]
 impl<M: Model + Send + Sync + Clone + 'static> AgentBase for Agent<M> {
-    async fn run(self: Arc<Self>, query: String, reset:bool) -> Pin<Box<dyn Stream<Item = String> + Send + 'static>> {
-        info!("Agent::run() called with query: {}", query);
+    async fn run(
+        self: Arc<Self>,
+        session_id: String,
+        query: String,
+        images: Vec<String>,
+        reset: bool,
+        answers: Option<UnboundedReceiver<String>>,
+    ) -> AgentStream {
+        info!("Agent::run() called for session {} with query: {}", session_id, query);
+        // Runs of the same session are serialized: the memory stays locked until the run ends.
+        let mut memory = self.sessions.get(&session_id).await.lock_owned().await;
         if reset {
             info!("Resetting agent memory");
-            let mut memory = self.agent_memory.lock().await;
             memory.reset();
         } else {
             info!("Continuing with existing agent memory");
         }
 
+        let cancel = CancellationToken::new();
+        self.runs
+            .lock()
+            .unwrap()
+            .insert(session_id.clone(), cancel.clone());
+
         let agent = self.clone();
         let max_steps = agent.max_steps;
-        agent._run_stream(query.clone(), max_steps, vec![]).await
+        agent._run_stream(memory, query.clone(), max_steps, images, answers, cancel).await
     }
 
     async fn _run_stream(
         self: Arc<Self>,
+        mut memory: OwnedMutexGuard<AgentMemory>,
         task: String,
         max_steps: usize,
-        _images: Vec<String>,
-    ) -> Pin<Box<dyn Stream<Item = String> + Send + 'static>> {
-        let model = self.model.clone();
-        let stream_outputs = self.stream_outputs;
-        Box::pin(stream! {
-            for step in 1..=max_steps {
-                let task_str = task.clone();
-                // Planning phase
-                let plan_output = self.plan(&task_str, step == 1).await;
-                let mut plan_stream = match plan_output {
-                    PlanOutput::Stream(s) => s,
-                    PlanOutput::Text(t) => Box::pin(stream! { yield t.clone() }),
-                };
-                let mut buffer = String::new();
-                while let Some(chunk) = plan_stream.next().await {
-                    buffer.push_str(&chunk);
-                    yield chunk;
-                }
-                let plan_for_generation = format!(
-                    "Here are the facts I know and the plan of action that I will follow to solve the task:\n```\n{}\n```",
-                    buffer
-                );
-                info!("Plan for generation (step {}): {}", step, plan_for_generation);
-
-                // Generation phase
-                let mut messages = Vec::new();
-                messages.push(HashMap::from([
-                    ("role".into(), "system".into()),
-                    ("content".into(), plan_for_generation.clone()),
-                ]));
-                messages.push(HashMap::from([
-                    ("role".into(), "user".into()),
-                    ("content".into(), task_str.clone()),
-                ]));
-
-                if stream_outputs {
-                    match model.async_generate_stream(messages.clone()).await {
-                        Ok(mut gen_stream) => {
-                            while let Some(res) = gen_stream.next().await {
-                                let chunk = String::from_utf8_lossy(&res.unwrap_or_default()).to_string();
-                                yield chunk;
-                            }
+        images: Vec<String>,
+        answers: Option<UnboundedReceiver<String>>,
+        cancel: CancellationToken,
+    ) -> AgentStream {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let events = RunEvents {
+            events: UnboundedReceiverStream::new(rx),
+            _cancel_on_drop: cancel.clone().drop_guard(),
+        };
+        tokio::spawn(async move {
+            memory.steps.push(Step::Task(TaskStep {
+                task: task.clone(),
+                task_images: (!images.is_empty()).then_some(images),
+            }));
+
+            // Tools that ask the user are only offered to runs that have one
+            memory.system_prompt.system_prompt = match answers {
+                Some(_) => self.render(&self.prompt.system_prompt, json!({}), true),
+                None => self.sessions.system_prompt().to_string(),
+            };
+            let mut run = RunState { executor: None, answers };
+            // Cancellation interrupts the current phase: its model call or tool calls are
+            // dropped, and the phase is not recorded, so that memory only holds complete steps.
+            let stopped = self.stopped(&cancel);
+            tokio::pin!(stopped);
+            let end = 'run: {
+                for step_number in 1..=max_steps {
+                    // Planning phase
+                    if self.should_plan(step_number) {
+                        let remaining_steps = max_steps - step_number + 1;
+                        let planned = tokio::select! {
+                            reason = &mut stopped => break 'run RunEnd::Cancelled(reason),
+                            planned = self.plan(&mut memory, &task, step_number == 1, remaining_steps, &run, &tx) => planned,
+                        };
+                        if let Err(err) = planned {
+                            error!("Planning failed at step {}: {}", step_number, err);
+                            let _ = tx.send(AgentEvent::Error { error: err });
+                            break 'run RunEnd::Finished;
                         }
+                    }
+
+                    // Action phase
+                    let stepped = tokio::select! {
+                        reason = &mut stopped => break 'run RunEnd::Cancelled(reason),
+                        stepped = self.step(&mut memory, step_number, &mut run, &tx) => stepped,
+                    };
+                    let final_answer = match stepped {
+                        Ok(final_answer) => final_answer,
                         Err(err) => {
-                            info!("Generation stream error: {:?}", err);
-                            yield String::new();
+                            error!("Step {} failed: {}", step_number, err);
+                            let _ = tx.send(AgentEvent::Error { error: err });
+                            break 'run RunEnd::Finished;
                         }
+                    };
+                    info!("Step {} completed", step_number);
+
+                    if let Some(answer) = final_answer {
+                        let output = match answer {
+                            Value::String(s) => s,
+                            other => other.to_string(),
+                        };
+                        memory.steps.push(Step::FinalAnswer(FinalAnswerStep { output: output.clone() }));
+                        let _ = tx.send(AgentEvent::FinalAnswer { answer: output, best_effort: false });
+                        break 'run RunEnd::Finished;
+                    }
+                }
+
+                // Out of steps: answer from what the run has gathered so far
+                info!("Reached max steps ({}), providing a best-effort answer", max_steps);
+                let _ = tx.send(text_delta(
+                    "\nReached the step limit without a final answer. Best-effort answer:\n".to_string(),
+                ));
+                let answered = tokio::select! {
+                    reason = &mut stopped => break 'run RunEnd::Cancelled(reason),
+                    answered = self.provide_final_answer(&mut memory, &task, max_steps + 1, &tx) => answered,
+                };
+                match answered {
+                    Ok(output) => {
+                        memory.steps.push(Step::FinalAnswer(FinalAnswerStep { output: output.clone() }));
+                        let _ = tx.send(AgentEvent::FinalAnswer { answer: output, best_effort: true });
+                    }
+                    Err(err) => {
+                        error!("Final answer synthesis failed: {}", err);
+                        let _ = tx.send(AgentEvent::Error { error: err });
                     }
-                } else {
-                    let text = model.async_generate(messages).await;
-                    yield text;
                 }
-                info!("Step {} completed", step);
+                RunEnd::Finished
+            };
+
+            if let RunEnd::Cancelled(reason) = end {
+                info!("Run {}", reason);
+                let _ = tx.send(AgentEvent::Cancelled { reason });
             }
-        })
+            // The run is over: there is nothing left to cancel, nor to remember
+            cancel.cancel();
+            self.runs.lock().unwrap().retain(|_, run| !run.is_cancelled());
+            info!(
+                "Run finished: {:?} (session total: {:?})",
+                memory.get_run_token_usage(),
+                memory.get_token_usage()
+            );
+        });
+        Box::pin(events)
     }
 
-    async fn step(&self, _state: &str) -> String {
-        "Agent::step() not implemented".to_string()
-    }
+    async fn step(
+        &self,
+        memory: &mut AgentMemory,
+        step_number: usize,
+        run: &mut RunState,
+        output: &UnboundedSender<AgentEvent>,
+    ) -> Result<Option<Value>, ModelError> {
+        let input_messages = memory.write_memory_to_messages(false);
+        let mut action_step = ActionStep {
+            step_number,
+            timing: Timing::start(),
+            tool_calls: None,
+            error: None,
+            model_output_message: None,
+            model_output: None,
+            code_action: None,
+            observations: None,
+            observations_images: None,
+            structured_observations: None,
+            action_output: None,
+            token_usage: None,
+            is_final_answer: false,
+        };
 
-    async fn plan(&self, state: &str, is_initial: bool) -> PlanOutput {
-        let start = Instant::now();
-        let tools_str = self
-            .available_actions
-            .iter()
-            .map(|a| a.as_str())
-            .collect::<Vec<_>>()
-            .join("\n");
-        let managed_agents = ""; // 필요 시 채우기
+        // Generation phase: code agents write code rather than calling tools natively
+        let tools = match self.code_execution {
+            Some(_) => vec![],
+            None => self.actions(run.answers.is_some()).map(|a| a.tool_schema()).collect(),
+        };
+        let response = match self.generate(input_messages, tools, output, text_delta).await {
+            Ok(response) => response,
+            Err(err) => {
+                action_step.error = Some(err.to_string());
+                action_step.timing.finish();
+                memory.steps.push(Step::Action(action_step));
+                return Err(err);
+            }
+        };
+        action_step.token_usage = response.token_usage;
+        action_step.model_output_message = Some(
+            ChatMessage::assistant(response.content.clone())
+                .with_tool_calls(response.tool_calls.clone()),
+        );
+        action_step.model_output = Some(response.content.clone());
 
+        match &self.code_execution {
+            Some(config) => self.run_code(&mut action_step, run, config, output).await,
+            None => {
+                self.call_tools(&mut action_step, response.tool_calls, &mut run.answers, output)
+                    .await
+            }
+        }
+        action_step.timing.finish();
+        let _ = output.send(AgentEvent::StepFinished {
+            step_number,
+            timing: action_step.timing,
+            usage: action_step.token_usage,
+            error: action_step.error.clone(),
+        });
+        let final_answer = action_step
+            .is_final_answer
+            .then(|| action_step.action_output.clone())
+            .flatten();
+        memory.steps.push(Step::Action(action_step));
+        Ok(final_answer)
+    }
+
+    async fn plan(
+        &self,
+        memory: &mut AgentMemory,
+        task: &str,
+        is_initial: bool,
+        remaining_steps: usize,
+        run: &RunState,
+        output: &UnboundedSender<AgentEvent>,
+    ) -> Result<(), ModelError> {
+        let with_user = run.answers.is_some();
+        let variables = json!({ "task": task, "remaining_steps": remaining_steps });
         let input_messages = if is_initial {
-            vec![HashMap::from([
-                ("role".into(), "user".into()),
-                (
-                    "content".into(),
-                    self.prompt
-                        .planning
-                        .initial_plan
-                        .replace("{task}", state)
-                        .replace("{tools}", &tools_str)
-                        .replace("{managed_agents}", managed_agents),
-                ),
-            ])]
+            vec![ChatMessage::user(
+                self.render(&self.prompt.planning.initial_plan, variables, with_user),
+            )]
         } else {
-            vec![
-                HashMap::from([
-                    ("role".into(), "system".into()),
-                    (
-                        "content".into(),
-                        self.prompt.planning.update_plan_pre_messages.clone(),
-                    ),
-                ]),
-                // TODO: memory 메시지 삽입
-                HashMap::from([
-                    ("role".into(), "user".into()),
-                    (
-                        "content".into(),
-                        self.prompt
-                            .planning
-                            .update_plan_post_messages
-                            .replace("{task}", state),
+            replay_history(
+                memory,
+                self.render(&self.prompt.planning.update_plan_pre_messages, variables.clone(), with_user),
+                self.render(&self.prompt.planning.update_plan_post_messages, variables, with_user),
+            )
+        };
+        let mut planning_step = PlanningStep {
+            model_output_message: None,
+            plan: String::new(),
+            timing: Timing::start(),
+            token_usage: None,
+            error: None,
+        };
+        let result = self
+            .generate(input_messages, vec![], output, |text| AgentEvent::PlanDelta { text })
+            .await;
+        planning_step.timing.finish();
+        let response = match result {
+            Ok(response) => response,
+            Err(err) => {
+                planning_step.error = Some(err.to_string());
+                memory.steps.push(Step::Planning(planning_step));
+                return Err(err);
+            }
+        };
+        let timing = planning_step.timing;
+        info!("Plan generated in {:.2} s", timing.end_time - timing.start_time);
+        planning_step.model_output_message = Some(ChatMessage::assistant(response.content.clone()));
+        planning_step.plan = response.content;
+        planning_step.token_usage = response.token_usage;
+        memory.steps.push(Step::Planning(planning_step));
+        Ok(())
+    }
+
+    async fn session_usage(&self, session_id: &str) -> Option<SessionUsage> {
+        let memory = self.sessions.find(session_id).await?;
+        let memory = memory.lock().await;
+        Some(SessionUsage {
+            last_run: memory.get_run_token_usage(),
+            session: memory.get_token_usage(),
+        })
+    }
+
+    async fn cancel(&self, session_id: &str) -> bool {
+        match self.runs.lock().unwrap().get(session_id) {
+            Some(cancel) if !cancel.is_cancelled() => {
+                info!("Cancelling the run of session {}", session_id);
+                cancel.cancel();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    async fn restore(&self, session_id: &str, steps: Vec<Step>) -> bool {
+        self.sessions.restore(session_id, steps).await
+    }
+
+    async fn forget(&self, session_id: &str) {
+        self.sessions.remove(session_id).await;
+        self.runs.lock().unwrap().remove(session_id);
+    }
+
+    async fn solve(self: Arc<Self>, task: String) -> Solution {
+        let memory = self.sessions.detached();
+        let max_steps = self.max_steps;
+        let mut stream = self
+            ._run_stream(memory.clone().lock_owned().await, task, max_steps, vec![], None, CancellationToken::new())
+            .await;
+        let mut final_answer = Ok(None);
+        while let Some(event) = stream.next().await {
+            match event {
+                AgentEvent::FinalAnswer { answer, .. } => final_answer = Ok(Some(answer)),
+                AgentEvent::Error { error } => final_answer = Err(error),
+                _ => {}
+            }
+        }
+        // Waits for the run to release the memory
+        let token_usage = memory.lock().await.get_token_usage();
+        Solution { final_answer, token_usage }
+    }
+}
+
+impl<M: Model + Send + Sync + Clone + 'static> Agent<M> {
+    /// Wraps the agent so that a manager agent can call it as a tool named `name`.
+    pub fn into_managed_agent(self, name: impl Into<String>, description: impl Into<String>) -> ManagedAgent {
+        let task_template = self.prompt.managed_agent.task.clone();
+        let report_template = self.prompt.managed_agent.report.clone();
+        ManagedAgent {
+            info: ActionBase {
+                name: name.into(),
+                description: description.into(),
+                parameters: vec![
+                    Parameter::new(
+                        "task",
+                        "Long detailed description of the task.",
+                        json!({ "type": "string" }),
                     ),
-                ]),
-            ]
+                    Parameter::new(
+                        "additional_args",
+                        "Dictionary of extra inputs to pass to the managed agent, e.g. images, dataframes, or any other contextual data it may need.",
+                        json!({ "type": "object" }),
+                    )
+                    .optional(),
+                ],
+                output_schema: json!({ "type": "string" }),
+            },
+            agent: Arc::new(self),
+            task_template,
+            report_template,
+        }
+    }
+}
+
+/// An agent exposed to a manager agent as a tool: it takes a task and reports its final answer.
+pub struct ManagedAgent {
+    pub info: ActionBase,
+    agent: Arc<dyn AgentBase + Send + Sync>,
+    task_template: String,
+    report_template: String,
+}
+
+#[async_trait]
+impl Action for ManagedAgent {
+    fn get_info(&self) -> &ActionBase {
+        &self.info
+    }
+
+    fn get_parameters(&self) -> &Vec<Parameter> {
+        &self.info.parameters
+    }
+
+    fn is_managed_agent(&self) -> bool {
+        true
+    }
+
+    async fn act(&self, inputs: Vec<ActionInput>) -> Observation {
+        info!("ManagedAgent {} called", self.info.name);
+        let inputs = self.prepare_inputs(inputs);
+        let task = inputs.get("task").map(|input| input.value.clone()).unwrap_or_default();
+        let variables = json!({ "name": self.info.name, "task": task });
+        let mut full_task = match populate_template(&self.task_template, &variables) {
+            Ok(full_task) => full_task,
+            Err(err) => return Observation::error(format!("Failed to render the task: {:#}", err)),
         };
-        if self.stream_outputs {
-            let raw_stream = match self.model.async_generate_stream(input_messages).await {
-                Ok(s) => s,
-                Err(err) => {
-                    info!("Stream generation error: {:?}", err);
-                    return PlanOutput::Text(String::new());
+        if let Some(additional_args) = inputs.get("additional_args").filter(|input| input.value != "{}") {
+            full_task.push_str(&format!(
+                "\nYou have been provided with these additional arguments, that you can access directly using the keys as variables:\n{}.",
+                additional_args.value
+            ));
+        }
+
+        let solution = self.agent.clone().solve(full_task).await;
+        let mut observation = match solution.final_answer {
+            Ok(final_answer) => {
+                let final_answer =
+                    final_answer.unwrap_or_else(|| "The agent did not reach a final answer.".to_string());
+                let variables = json!({ "name": self.info.name, "final_answer": final_answer });
+                match populate_template(&self.report_template, &variables) {
+                    Ok(report) => Observation::success(report),
+                    Err(err) => Observation::error(format!("Failed to render the report: {:#}", err)),
                 }
+            }
+            Err(err) => {
+                error!("ManagedAgent {} failed: {}", self.info.name, err);
+                Observation::error(format!("Managed agent {} failed: {}", self.info.name, err))
+            }
+        };
+        // The manager accounts for the tokens of its team members
+        observation.token_usage = Some(solution.token_usage);
+        observation
+    }
+}
+
+/// Extracts the code written between the code block tags, joining multiple blocks.
+fn parse_code_blobs(text: &str) -> Option<String> {
+    let mut blobs = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(CODE_BLOCK_OPENING_TAG) {
+        rest = &rest[start + CODE_BLOCK_OPENING_TAG.len()..];
+        let end = rest.find(CODE_BLOCK_CLOSING_TAG).unwrap_or(rest.len());
+        blobs.push(rest[..end].trim());
+        rest = &rest[end..];
+    }
+    blobs.retain(|blob| !blob.is_empty());
+    (!blobs.is_empty()).then(|| blobs.join("\n\n"))
+}
+
+/// Extracts the first `{"name": ..., "arguments": ...}` blob from a model output,
+/// preferring the one following an `Action:` marker.
+fn parse_tool_call(text: &str) -> Option<(String, Value)> {
+    let start = text.find("Action:").map_or(0, |i| i + "Action:".len());
+    let text = &text[start..];
+    text.match_indices('{').find_map(|(i, _)| {
+        let mut values = serde_json::Deserializer::from_str(&text[i..]).into_iter::<Value>();
+        match values.next() {
+            Some(Ok(Value::Object(blob))) => {
+                let name = blob.get("name")?.as_str()?.to_string();
+                Some((name, blob.get("arguments").cloned().unwrap_or(Value::Null)))
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Normalizes tool call arguments into a keyword map. A bare value is bound to the
+/// first declared parameter, as in `{"name": "web_search", "arguments": "query"}`.
+fn to_arguments(raw_arguments: Value, parameters: &[Parameter]) -> HashMap<String, Value> {
+    match raw_arguments {
+        Value::Object(map) => map.into_iter().collect(),
+        Value::Null => HashMap::new(),
+        other => parameters
+            .first()
+            .map(|param| HashMap::from([(param.name.clone(), other)]))
+            .unwrap_or_default(),
+    }
+}
+
+fn to_action_inputs(arguments: &HashMap<String, Value>, parameters: &[Parameter]) -> Vec<ActionInput> {
+    arguments
+        .iter()
+        .map(|(key, value)| ActionInput {
+            key: key.clone(),
+            value: match value {
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            },
+            dtype: parameters
+                .iter()
+                .find(|param| &param.name == key)
+                .map_or("string", |param| param.json_type())
+                .to_string(),
+        })
+        .collect()
+}
+
+/// Adds the tokens used by an action to those of the step that called it.
+/// Replays the history so far without the system prompt, between `pre` as a system message and
+/// `post` as a user message.
+fn replay_history(memory: &AgentMemory, pre: String, post: String) -> Vec<ChatMessage> {
+    let mut messages = vec![ChatMessage::system(pre)];
+    messages.extend(memory.write_memory_to_messages(false).into_iter().skip(1));
+    messages.push(ChatMessage::user(post));
+    messages
+}
+
+fn add_token_usage(action_step: &mut ActionStep, usage: Option<TokenUsage>) {
+    if let Some(usage) = usage {
+        *action_step.token_usage.get_or_insert_default() += usage;
+    }
+}
+
+fn text_delta(text: String) -> AgentEvent {
+    AgentEvent::TextDelta { text }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    use crate::actions::AskUserAction;
+
+    /// A model that answers with the responses it was given, in order, and records its inputs
+    /// and the names of the tools it was offered.
+    #[derive(Clone, Default)]
+    pub(crate) struct ScriptedModel {
+        responses: Arc<Mutex<VecDeque<Result<ModelResponse, ModelError>>>>,
+        pub(crate) inputs: Arc<Mutex<Vec<Vec<ChatMessage>>>>,
+        pub(crate) tools: Arc<Mutex<Vec<Vec<String>>>>,
+        /// Whether calls past the script never answer, instead of failing the test.
+        hangs: bool,
+    }
+
+    impl ScriptedModel {
+        pub(crate) fn new(responses: Vec<Result<ModelResponse, ModelError>>) -> Self {
+            Self {
+                responses: Arc::new(Mutex::new(responses.into())),
+                ..Default::default()
+            }
+        }
+
+        /// A model whose calls past `responses` never answer, for runs to be stopped midway.
+        pub(crate) fn hanging(responses: Vec<Result<ModelResponse, ModelError>>) -> Self {
+            Self { hangs: true, ..Self::new(responses) }
+        }
+
+        /// Scripts one more response.
+        pub(crate) fn push(&self, response: Result<ModelResponse, ModelError>) {
+            self.responses.lock().unwrap().push_back(response);
+        }
+
+        /// Waits until the model has been called `calls` times.
+        pub(crate) async fn called(&self, calls: usize) {
+            while self.inputs.lock().unwrap().len() < calls {
+                tokio::task::yield_now().await;
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Model for ScriptedModel {
+        async fn async_generate_stream(
+            &self,
+            messages: Vec<ChatMessage>,
+            tools: Vec<ToolSchema>,
+        ) -> Result<Pin<Box<dyn Stream<Item = Result<ModelDelta, ModelError>> + Send>>, ModelError> {
+            self.inputs.lock().unwrap().push(messages);
+            self.tools.lock().unwrap().push(tools.into_iter().map(|tool| tool.name).collect());
+            let Some(response) = self.responses.lock().unwrap().pop_front() else {
+                assert!(self.hangs, "the model was called more often than scripted");
+                return Ok(Box::pin(futures::stream::pending()));
             };
-            let mapped = raw_stream.map(|chunk_res| {
-                let bytes = chunk_res.unwrap_or_default();
-                String::from_utf8_lossy(&bytes).to_string()
-            });
-            // Box and pin the stream
-            let boxed: Pin<Box<dyn Stream<Item = String> + Send>> = Box::pin(mapped);
-            info!("Plan generated in {} ms", start.elapsed().as_millis());
-            PlanOutput::Stream(boxed)
-        } else {
-            let plan_text = self.model.async_generate(input_messages).await;
-            info!("Plan generated in {} ms", start.elapsed().as_millis());
-            PlanOutput::Text(plan_text)
+            let response = response?;
+            let mut deltas = vec![Ok(ModelDelta::Text(response.content))];
+            if !response.tool_calls.is_empty() {
+                deltas.push(Ok(ModelDelta::ToolCalls(response.tool_calls)));
+            }
+            deltas.extend(response.token_usage.map(|usage| Ok(ModelDelta::Usage(usage))));
+            Ok(Box::pin(futures::stream::iter(deltas)))
+        }
+    }
+
+    async fn events(stream: AgentStream) -> Vec<AgentEvent> {
+        stream.collect().await
+    }
+
+    /// A response with at most one tool call, named after the tool.
+    pub(crate) fn response(
+        content: &str,
+        tool_call: Option<(&str, Value)>,
+        usage: TokenUsage,
+    ) -> Result<ModelResponse, ModelError> {
+        Ok(ModelResponse {
+            content: content.to_string(),
+            tool_calls: tool_call
+                .into_iter()
+                .map(|(name, arguments)| ToolCall {
+                    id: format!("call_{}", name),
+                    name: name.to_string(),
+                    arguments: serde_json::from_value(arguments).unwrap(),
+                    arguments_error: None,
+                })
+                .collect(),
+            token_usage: Some(usage),
+        })
+    }
+
+    #[tokio::test]
+    async fn manager_delegates_to_managed_agent() {
+        let researcher = ScriptedModel::new(vec![response(
+            "",
+            Some(("final_answer", json!({ "answer": "Paris" }))),
+            TokenUsage::new(10, 5),
+        )]);
+        let manager = ScriptedModel::new(vec![
+            response(
+                "",
+                Some(("researcher", json!({ "task": "Find the capital of France." }))),
+                TokenUsage::new(100, 20),
+            ),
+            response("", Some(("final_answer", json!({ "answer": "Paris" }))), TokenUsage::new(200, 30)),
+        ]);
+        let agent = Arc::new(Agent::new(manager.clone(), 3, vec![], false).with_managed_agent(
+            Agent::new(researcher.clone(), 3, vec![], false)
+                .into_managed_agent("researcher", "Looks things up on the web."),
+        ));
+
+        let events = events(agent.clone().run("session".to_string(), "task".to_string(), vec![], false, None).await).await;
+
+        // The manager's system prompt lists the team member
+        let system_prompt = manager.inputs.lock().unwrap()[0][0].text();
+        assert!(system_prompt.contains("- researcher: Looks things up on the web."), "{}", system_prompt);
+        // The researcher gets the task wrapped for a managed agent
+        let task = researcher.inputs.lock().unwrap()[0].last().unwrap().text();
+        assert!(task.contains("You're a helpful agent named 'researcher'."), "{}", task);
+        assert!(task.contains("Find the capital of France."), "{}", task);
+        // Its final answer is reported back to the manager
+        let report = events
+            .iter()
+            .find_map(|event| match event {
+                AgentEvent::ToolResult { name, observation, .. } if name == "researcher" => Some(observation),
+                _ => None,
+            })
+            .expect("the researcher was called");
+        assert_eq!(report.result, "Here is the final answer from your managed agent 'researcher':\nParis");
+        assert_eq!(report.token_usage, Some(TokenUsage::new(10, 5)));
+        assert!(matches!(events.last(), Some(AgentEvent::FinalAnswer { answer, .. }) if answer == "Paris"));
+
+        // The researcher's tokens count towards the manager's session
+        let usage = agent.session_usage("session").await.unwrap();
+        assert_eq!(usage.last_run, TokenUsage::new(310, 55));
+        assert_eq!(usage.session, TokenUsage::new(310, 55));
+    }
+
+    #[tokio::test]
+    async fn unreadable_arguments_are_reported_to_the_model() {
+        let mut unreadable = response("", Some(("final_answer", json!({}))), TokenUsage::new(1, 1)).unwrap();
+        unreadable.tool_calls[0].arguments_error = Some("the arguments are not valid JSON".to_string());
+        let model = ScriptedModel::new(vec![
+            Ok(unreadable),
+            response("", Some(("final_answer", json!({ "answer": "done" }))), TokenUsage::new(1, 1)),
+        ]);
+        let agent = Arc::new(Agent::new(model.clone(), 3, vec![], false));
+
+        let events = events(agent.clone().run("session".to_string(), "task".to_string(), vec![], false, None).await).await;
+
+        let error = events.iter().find_map(|event| match event {
+            AgentEvent::StepFinished { step_number: 1, error, .. } => error.clone(),
+            _ => None,
+        });
+        assert_eq!(
+            error.as_deref(),
+            Some("Invalid call to tool final_answer: the arguments are not valid JSON.")
+        );
+        let retry = model.inputs.lock().unwrap()[1].last().unwrap().text();
+        assert!(retry.starts_with("Error occurred: Invalid call to tool final_answer"), "{}", retry);
+        assert!(matches!(events.last(), Some(AgentEvent::FinalAnswer { answer, .. }) if answer == "done"));
+    }
+
+    #[tokio::test]
+    async fn ask_user_is_only_offered_to_runs_with_a_user() {
+        let final_answer = || response("", Some(("final_answer", json!({ "answer": "done" }))), TokenUsage::new(1, 1));
+        let model = ScriptedModel::new(vec![final_answer(), final_answer()]);
+        let agent = Arc::new(Agent::new(model.clone(), 3, vec![Box::new(AskUserAction::new())], false));
+
+        events(agent.clone().run("session".to_string(), "task".to_string(), vec![], false, None).await).await;
+        let (_answers, receiver) = mpsc::unbounded_channel();
+        events(agent.clone().run("session".to_string(), "task".to_string(), vec![], false, Some(receiver)).await).await;
+
+        let offered = model.tools.lock().unwrap().clone();
+        assert!(!offered[0].contains(&"ask_user".to_string()), "{:?}", offered[0]);
+        assert!(offered[1].contains(&"ask_user".to_string()), "{:?}", offered[1]);
+        let inputs = model.inputs.lock().unwrap();
+        assert!(!inputs[0][0].text().contains("ask_user"));
+        assert!(inputs[1][0].text().contains("ask_user"));
+    }
+
+    #[tokio::test]
+    async fn best_effort_answers_are_replayed_once() {
+        let model = ScriptedModel::new(vec![
+            response("", Some(("final_answer", json!({}))), TokenUsage::new(10, 1)),
+            response("Paris, most likely.", None, TokenUsage::new(20, 2)),
+            response("", Some(("final_answer", json!({ "answer": "Lyon" }))), TokenUsage::new(30, 3)),
+        ]);
+        let agent = Arc::new(Agent::new(model.clone(), 1, vec![], false));
+
+        let first = events(agent.clone().run("session".to_string(), "capital".to_string(), vec![], false, None).await).await;
+        assert!(matches!(
+            first.last(),
+            Some(AgentEvent::FinalAnswer { answer, best_effort: true }) if answer == "Paris, most likely."
+        ));
+        let usage = agent.session_usage("session").await.unwrap();
+        assert_eq!(usage.last_run, TokenUsage::new(30, 3));
+
+        events(agent.clone().run("session".to_string(), "second city".to_string(), vec![], false, None).await).await;
+        let replayed = model.inputs.lock().unwrap()[2].clone();
+        let texts: Vec<_> = replayed.iter().map(|message| message.text()).collect();
+        assert_eq!(texts.iter().filter(|text| text.contains("Paris")).count(), 1, "{:#?}", texts);
+        assert!(!texts.iter().any(|text| text.contains("Reached max steps")), "{:#?}", texts);
+        let answer = texts.iter().position(|text| text == "Paris, most likely.").unwrap();
+        assert_eq!(replayed[answer].role, crate::models::MessageRole::Assistant);
+        assert_eq!(texts[answer + 1], "New task:\nsecond city");
+    }
+
+    fn answer(answer: &str) -> Result<ModelResponse, ModelError> {
+        response("", Some(("final_answer", json!({ "answer": answer }))), TokenUsage::new(10, 1))
+    }
+
+    #[tokio::test]
+    async fn cancelled_runs_free_their_session() {
+        let model = ScriptedModel::hanging(vec![]);
+        let agent = Arc::new(Agent::new(model.clone(), 3, vec![], false));
+
+        let stream = agent.clone().run("session".to_string(), "task".to_string(), vec![], false, None).await;
+        model.called(1).await;
+        assert!(agent.cancel("session").await);
+        let cancelled = events(stream).await;
+        assert!(matches!(
+            cancelled.last(),
+            Some(AgentEvent::Cancelled { reason: CancelReason::Cancelled })
+        ));
+        assert!(agent.runs.lock().unwrap().is_empty());
+        assert!(!agent.cancel("session").await);
+
+        // The interrupted step is not recorded, and the session takes the next run
+        model.push(answer("Paris"));
+        let answered = events(agent.clone().run("session".to_string(), "again".to_string(), vec![], false, None).await).await;
+        assert!(matches!(answered.last(), Some(AgentEvent::FinalAnswer { answer, .. }) if answer == "Paris"));
+        let texts: Vec<_> = model.inputs.lock().unwrap()[1].iter().map(|message| message.text()).collect();
+        assert_eq!(texts[1..], ["New task:\ntask", "New task:\nagain"]);
+        assert!(agent.runs.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn dropping_the_events_cancels_the_run() {
+        let model = ScriptedModel::hanging(vec![]);
+        let agent = Arc::new(Agent::new(model.clone(), 3, vec![], false));
+
+        let stream = agent.clone().run("session".to_string(), "task".to_string(), vec![], false, None).await;
+        model.called(1).await;
+        // The client goes away
+        drop(stream);
+
+        model.push(answer("Paris"));
+        let next = agent.clone().run("session".to_string(), "again".to_string(), vec![], false, None);
+        let answered = events(tokio::time::timeout(Duration::from_secs(5), next).await.expect("the session stayed locked")).await;
+        assert!(matches!(answered.last(), Some(AgentEvent::FinalAnswer { answer, .. }) if answer == "Paris"));
+        assert!(agent.runs.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn runs_time_out() {
+        let model = ScriptedModel::hanging(vec![]);
+        let agent = Arc::new(
+            Agent::new(model.clone(), 3, vec![], false).with_run_timeout(Some(Duration::from_millis(50))),
+        );
+
+        let timed_out = events(agent.clone().run("session".to_string(), "task".to_string(), vec![], false, None).await).await;
+        assert!(matches!(
+            timed_out.last(),
+            Some(AgentEvent::Cancelled { reason: CancelReason::TimedOut })
+        ));
+        assert!(agent.runs.lock().unwrap().is_empty());
+
+        model.push(answer("Paris"));
+        let answered = events(agent.clone().run("session".to_string(), "again".to_string(), vec![], false, None).await).await;
+        assert!(matches!(answered.last(), Some(AgentEvent::FinalAnswer { answer, .. }) if answer == "Paris"));
+    }
+
+    #[tokio::test]
+    async fn code_agents_run_the_code_they_write() {
+        let model = ScriptedModel::new(vec![
+            response(
+                "Thought: compute it in two blocks.\n<code>\nx = 6\n</code>\nthen\n<code>\nx *= 7\nprint(x)\n</code>",
+                None,
+                TokenUsage::new(10, 5),
+            ),
+            response("Thought: I know it now.", None, TokenUsage::new(20, 5)),
+            response("<code>\nfinal_answer(f\"The answer is {x}\")\n</code>", None, TokenUsage::new(30, 5)),
+        ]);
+        let config = PythonExecutorConfig {
+            authorized_imports: vec!["math".to_string()],
+            ..Default::default()
+        };
+        let agent = Arc::new(Agent::new_code_agent(model.clone(), 3, vec![], false, config));
+
+        let events = events(agent.clone().run("session".to_string(), "task".to_string(), vec![], false, None).await).await;
+
+        // Code agents are told how to write code, and offered no tools to call natively
+        let system_prompt = model.inputs.lock().unwrap()[0][0].text();
+        assert!(system_prompt.contains("<code>") && system_prompt.contains("math"), "{}", system_prompt);
+        assert!(model.tools.lock().unwrap().iter().all(Vec::is_empty));
+        let code = events.iter().find_map(|event| match event {
+            AgentEvent::ToolCallStarted { name, arguments, .. } if name == PYTHON_INTERPRETER => {
+                arguments["code"].as_str().map(str::to_string)
+            }
+            _ => None,
+        });
+        assert_eq!(code.as_deref(), Some("x = 6\n\nx *= 7\nprint(x)"));
+        let observed = model.inputs.lock().unwrap()[1].last().unwrap().text();
+        assert!(observed.contains("42"), "{}", observed);
+        // Output without code is sent back, and the variables survive it
+        let retried = model.inputs.lock().unwrap()[2].last().unwrap().text();
+        assert!(retried.contains("Could not find a code block"), "{}", retried);
+        assert!(matches!(
+            events.last(),
+            Some(AgentEvent::FinalAnswer { answer, best_effort: false }) if answer == "The answer is 42"
+        ));
+    }
+
+    #[test]
+    fn planning_follows_the_interval() {
+        let cases: [(Option<usize>, [bool; 6]); 4] = [
+            (None, [false, false, false, false, false, false]),
+            (Some(0), [true, false, false, false, false, false]),
+            (Some(1), [true, true, true, true, true, true]),
+            (Some(2), [true, false, true, false, true, false]),
+        ];
+        for (planning_interval, expected) in cases {
+            let agent = Agent::new(ScriptedModel::default(), 6, vec![], false)
+                .with_planning_interval(planning_interval);
+            let planned = (1..=6).map(|step_number| agent.should_plan(step_number)).collect::<Vec<_>>();
+            assert_eq!(planned, expected, "planning_interval: {:?}", planning_interval);
         }
     }
+
+    #[tokio::test]
+    async fn failed_planning_is_recorded() {
+        let model = ScriptedModel::new(vec![Err(ModelError::Network("connection reset".to_string()))]);
+        let agent = Arc::new(
+            Agent::new(model, 3, vec![], true).with_planning_interval(Some(0)),
+        );
+
+        let events = events(agent.clone().run("session".to_string(), "task".to_string(), vec![], false, None).await).await;
+        assert!(matches!(events.last(), Some(AgentEvent::Error { error: ModelError::Network(_) })));
+
+        let memory = agent.sessions.find("session").await.unwrap();
+        let memory = memory.lock().await;
+        let Some(Step::Planning(planning_step)) = memory.steps.last() else {
+            panic!("the failed planning attempt was not recorded");
+        };
+        assert_eq!(planning_step.error.as_deref(), Some("model unreachable: connection reset"));
+        assert!(planning_step.model_output_message.is_none());
+        assert!(planning_step.timing.end_time >= planning_step.timing.start_time);
+        // The failed attempt is not replayed to the model as a plan
+        assert_eq!(memory.write_memory_to_messages(false).len(), 2);
+    }
 }
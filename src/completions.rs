@@ -0,0 +1,571 @@
+//! OpenAI-compatible Chat Completions API, so that existing SDKs and UIs can talk to the agent.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_stream::stream;
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::sse::{Event, KeepAlive, Sse},
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{error, info};
+
+use crate::agents::{self, AgentBase};
+use crate::events::{AgentEvent, CancelReason};
+use crate::memory::{FinalAnswerStep, Step, TaskStep, TokenUsage};
+use crate::models::ModelError;
+use crate::{AppState, SSE_KEEP_ALIVE_INTERVAL};
+
+#[derive(Deserialize)]
+pub struct ChatCompletionRequest {
+    /// Name of the agent to run.
+    model: String,
+    messages: Vec<RequestMessage>,
+    #[serde(default)]
+    stream: bool,
+    stream_options: Option<StreamOptions>,
+    /// Session to run in. Requests without one run in a session of their own.
+    user: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StreamOptions {
+    #[serde(default)]
+    include_usage: bool,
+}
+
+#[derive(Deserialize)]
+struct RequestMessage {
+    role: String,
+    content: Option<MessageContent>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+    /// Audio, files and the like, which the agent can't take.
+    #[serde(other)]
+    Unsupported,
+}
+
+#[derive(Deserialize)]
+struct ImageUrl {
+    /// A URL or a base64 data URL.
+    url: String,
+}
+
+impl MessageContent {
+    /// The text of the message, its parts joined by newlines.
+    fn text(&self) -> String {
+        match self {
+            MessageContent::Text(text) => text.clone(),
+            MessageContent::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ContentPart::Text { text } => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+
+    /// The URLs of the images of the message.
+    fn images(&self) -> Vec<String> {
+        match self {
+            MessageContent::Text(_) => vec![],
+            MessageContent::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ContentPart::ImageUrl { image_url } => Some(image_url.url.clone()),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+
+    fn is_supported(&self) -> bool {
+        match self {
+            MessageContent::Text(_) => true,
+            MessageContent::Parts(parts) => !parts.iter().any(|part| matches!(part, ContentPart::Unsupported)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ChatCompletion {
+    id: String,
+    object: &'static str,
+    created: u64,
+    model: String,
+    choices: Vec<Choice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<TokenUsage>,
+}
+
+#[derive(Serialize)]
+struct Choice {
+    index: usize,
+    /// Set in complete responses.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<ResponseMessage>,
+    /// Set in chunks.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delta: Option<ResponseMessage>,
+    finish_reason: Option<&'static str>,
+}
+
+/// The final answer goes to `content`; plans, generated text and observations that led to it go
+/// to `reasoning_content`.
+#[derive(Serialize, Default)]
+struct ResponseMessage {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reasoning_content: Option<String>,
+}
+
+/// Identifies one completion.
+struct Completion {
+    id: String,
+    created: u64,
+    model: String,
+}
+
+impl Completion {
+    fn new(model: String) -> Self {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        Self {
+            id: format!(
+                "chatcmpl-{:x}{:x}",
+                now.as_nanos(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            ),
+            created: now.as_secs(),
+            model,
+        }
+    }
+
+    fn response(&self, choice: Choice, usage: Option<TokenUsage>) -> ChatCompletion {
+        ChatCompletion {
+            id: self.id.clone(),
+            object: "chat.completion",
+            created: self.created,
+            model: self.model.clone(),
+            choices: vec![choice],
+            usage,
+        }
+    }
+
+    fn chunk(&self, delta: ResponseMessage, finish_reason: Option<&'static str>) -> ChatCompletion {
+        let choice = Choice {
+            index: 0,
+            message: None,
+            delta: Some(delta),
+            finish_reason,
+        };
+        ChatCompletion {
+            object: "chat.completion.chunk",
+            ..self.response(choice, None)
+        }
+    }
+}
+
+/// An error in the format of the OpenAI API.
+fn api_error(status: StatusCode, message: impl Into<String>, kind: &str) -> Response {
+    let body = json!({ "error": { "message": message.into(), "type": kind, "code": null } });
+    (status, Json(body)).into_response()
+}
+
+fn model_error(err: &ModelError) -> serde_json::Value {
+    // Reuse the serialized kind of the error, e.g. "rate_limit"
+    let kind = serde_json::to_value(err).ok().and_then(|value| value["kind"].as_str().map(str::to_string));
+    json!({ "error": { "message": err.to_string(), "type": kind, "code": null } })
+}
+
+/// A run stopped before it could answer, with the status it is reported with.
+fn cancelled_error(reason: CancelReason) -> (StatusCode, serde_json::Value) {
+    let (status, kind) = match reason {
+        CancelReason::Cancelled => (StatusCode::CONFLICT, "cancelled"),
+        CancelReason::TimedOut => (StatusCode::GATEWAY_TIMEOUT, "timeout"),
+    };
+    let body = json!({ "error": { "message": format!("The run was {}", reason), "type": kind, "code": null } });
+    (status, body)
+}
+
+/// A session that serves a single request, forgotten when the response is done with it.
+struct OneShotSession {
+    agent: Arc<dyn AgentBase + Send + Sync>,
+    session_id: String,
+}
+
+impl Drop for OneShotSession {
+    fn drop(&mut self) {
+        let agent = self.agent.clone();
+        let session_id = std::mem::take(&mut self.session_id);
+        tokio::spawn(async move { agent.forget(&session_id).await });
+    }
+}
+
+/// The conversation that led to the task, as memory steps: user messages are tasks, with their
+/// images, and assistant messages their answers. Other messages are left out.
+fn history(messages: &[RequestMessage]) -> Vec<Step> {
+    messages
+        .iter()
+        .filter_map(|message| {
+            let text = message.content.as_ref().map(MessageContent::text).unwrap_or_default();
+            match message.role.as_str() {
+                "user" => {
+                    let images = message.content.as_ref().map(MessageContent::images).unwrap_or_default();
+                    let task_images = (!images.is_empty()).then_some(images);
+                    Some(Step::Task(TaskStep { task: text, task_images }))
+                }
+                "assistant" if !text.is_empty() => Some(Step::FinalAnswer(FinalAnswerStep { output: text })),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// The instructions of the system and developer messages, which the agent gets along with the
+/// task since its own system prompt tells it how to act.
+fn instructions(messages: &[RequestMessage]) -> Option<String> {
+    let instructions = messages
+        .iter()
+        .filter(|message| matches!(message.role.as_str(), "system" | "developer"))
+        .filter_map(|message| message.content.as_ref().map(MessageContent::text))
+        .filter(|text| !text.is_empty())
+        .collect::<Vec<_>>();
+    (!instructions.is_empty()).then(|| instructions.join("\n"))
+}
+
+/// `GET /v1/models`: lists the agent, under its configured name.
+pub async fn models(State(state): State<Arc<AppState>>) -> Json<serde_json::Value> {
+    Json(json!({
+        "object": "list",
+        "data": [{ "id": state.agent_name, "object": "model", "created": 0, "owned_by": "agent-rs" }],
+    }))
+}
+
+/// `POST /v1/chat/completions`: runs the agent named by `model` on the last user message, after
+/// the instructions of the system and developer messages.
+///
+/// A conversation without assistant messages starts the session over. Later turns continue
+/// from the session's memory; a session the agent doesn't know yet, such as the session of its
+/// own that a request without `user` runs in, starts from the history sent by the client.
+pub async fn chat_completions(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<ChatCompletionRequest>,
+) -> Response {
+    if request.model != state.agent_name {
+        return api_error(
+            StatusCode::NOT_FOUND,
+            format!("The model '{}' does not exist", request.model),
+            "model_not_found",
+        );
+    }
+    let Some(last_user) = request.messages.iter().rposition(|message| message.role == "user") else {
+        return api_error(StatusCode::BAD_REQUEST, "No user message to answer", "invalid_request_error");
+    };
+    let Some(content) = request.messages[last_user].content.as_ref() else {
+        return api_error(StatusCode::BAD_REQUEST, "No user message to answer", "invalid_request_error");
+    };
+    if !request.messages.iter().filter_map(|message| message.content.as_ref()).all(MessageContent::is_supported) {
+        return api_error(
+            StatusCode::BAD_REQUEST,
+            "Only text and image content parts are supported",
+            "invalid_request_error",
+        );
+    }
+    let task = match instructions(&request.messages) {
+        Some(instructions) => format!("{}\n\n{}", instructions, content.text()),
+        None => content.text(),
+    };
+    let images = content.images();
+    let mut reset = !request.messages.iter().any(|message| message.role == "assistant");
+    let completion = Completion::new(request.model);
+    // Requests without a session are forgotten once answered
+    let (session_id, one_shot) = match request.user {
+        Some(user) => (user, None),
+        None => {
+            let session = OneShotSession {
+                agent: state.agent.clone(),
+                session_id: completion.id.clone(),
+            };
+            (completion.id.clone(), Some(session))
+        }
+    };
+    info!("Processing chat completion {} for session {}", completion.id, session_id);
+    let history = history(&request.messages[..last_user]);
+    if !history.is_empty() && state.agent.restore(&session_id, history).await {
+        reset = false;
+    }
+
+    let events = state.agent.clone().run(session_id.clone(), task, images, reset, None).await;
+    let mut events = match agents::started(events).await {
+        Ok(events) => events,
+        Err(err) => {
+            error!("Chat completion failed for session {}: {}", session_id, err);
+            return (err.status_code(), Json(model_error(&err))).into_response();
+        }
+    };
+
+    if !request.stream {
+        let mut reasoning = String::new();
+        let mut answer = String::new();
+        while let Some(event) = events.next().await {
+            match event {
+                AgentEvent::FinalAnswer { answer: final_answer, .. } => answer = final_answer,
+                AgentEvent::Error { error: err } => {
+                    error!("Chat completion failed for session {}: {}", session_id, err);
+                    return (err.status_code(), Json(model_error(&err))).into_response();
+                }
+                AgentEvent::Cancelled { reason } => {
+                    info!("Chat completion {} for session {}", reason, session_id);
+                    let (status, body) = cancelled_error(reason);
+                    return (status, Json(body)).into_response();
+                }
+                event => reasoning.extend(event.text()),
+            }
+        }
+        let usage = state.agent.session_usage(&session_id).await.map(|usage| usage.last_run);
+        let message = ResponseMessage {
+            role: Some("assistant"),
+            content: Some(answer),
+            reasoning_content: (!reasoning.is_empty()).then_some(reasoning),
+        };
+        let choice = Choice {
+            index: 0,
+            message: Some(message),
+            delta: None,
+            finish_reason: Some("stop"),
+        };
+        return Json(completion.response(choice, usage)).into_response();
+    }
+
+    let include_usage = request.stream_options.is_some_and(|options| options.include_usage);
+    let chunks = stream! {
+        let _one_shot = one_shot;
+        let role = ResponseMessage { role: Some("assistant"), ..Default::default() };
+        yield json!(completion.chunk(role, None));
+        while let Some(event) = events.next().await {
+            let delta = match event {
+                AgentEvent::FinalAnswer { answer, .. } => ResponseMessage {
+                    content: Some(answer),
+                    ..Default::default()
+                },
+                // The response has started: report the error in band, as OpenAI does, with no
+                // finish reason since the run didn't finish
+                AgentEvent::Error { error: err } => {
+                    error!("Chat completion failed for session {}: {}", session_id, err);
+                    yield model_error(&err);
+                    return;
+                }
+                AgentEvent::Cancelled { reason } => {
+                    info!("Chat completion {} for session {}", reason, session_id);
+                    yield cancelled_error(reason).1;
+                    return;
+                }
+                event => match event.text() {
+                    Some(text) => ResponseMessage { reasoning_content: Some(text), ..Default::default() },
+                    None => continue,
+                },
+            };
+            yield json!(completion.chunk(delta, None));
+        }
+        yield json!(completion.chunk(ResponseMessage::default(), Some("stop")));
+        if include_usage {
+            let usage = state.agent.session_usage(&session_id).await.map(|usage| usage.last_run);
+            let mut chunk = completion.chunk(ResponseMessage::default(), None);
+            chunk.choices.clear();
+            chunk.usage = usage;
+            yield json!(chunk);
+        }
+    };
+    let chunks = chunks
+        .map(|chunk| Event::default().json_data(chunk))
+        .chain(futures::stream::once(async { Ok(Event::default().data("[DONE]")) }));
+    Sse::new(chunks)
+        .keep_alive(KeepAlive::new().interval(SSE_KEEP_ALIVE_INTERVAL))
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agents::tests::{response, ScriptedModel};
+    use crate::agents::Agent;
+    use crate::models::{ContentPart as MessagePart, MessageRole};
+
+    fn state(model: &ScriptedModel) -> Arc<AppState> {
+        Arc::new(AppState {
+            agent: Arc::new(Agent::new(model.clone(), 3, vec![], false)),
+            agent_name: "agent-rs".to_string(),
+        })
+    }
+
+    #[tokio::test]
+    async fn requests_without_session_carry_their_history_and_are_forgotten() {
+        let model = ScriptedModel::new(vec![response(
+            "",
+            Some(("final_answer", json!({ "answer": "Rome." }))),
+            TokenUsage::new(10, 2),
+        )]);
+        let state = state(&model);
+        let agent = state.agent.clone();
+        let request = serde_json::from_value(json!({
+            "model": "agent-rs",
+            "messages": [
+                { "role": "system", "content": "Be brief." },
+                { "role": "user", "content": [
+                    { "type": "text", "text": "What is the capital of this country?" },
+                    { "type": "image_url", "image_url": { "url": "https://example.com/france.png" } },
+                ] },
+                { "role": "assistant", "content": "Paris." },
+                { "role": "user", "content": [
+                    { "type": "text", "text": "And of this one?" },
+                    { "type": "image_url", "image_url": { "url": "data:image/png;base64,aXRhbHk=" } },
+                ] },
+            ],
+        }))
+        .unwrap();
+
+        let response = chat_completions(State(state), Json(request)).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["choices"][0]["message"]["content"], "Rome.");
+        assert_eq!(body["usage"]["total_tokens"], 12);
+        let conversation: Vec<_> = model.inputs.lock().unwrap()[0]
+            .iter()
+            .skip(1)
+            .map(|message| {
+                let images: Vec<_> = message
+                    .content
+                    .iter()
+                    .filter_map(|part| match part {
+                        MessagePart::Image { url } => Some(url.as_str()),
+                        MessagePart::Text { .. } => None,
+                    })
+                    .collect();
+                (message.role, message.text(), images.join(" "))
+            })
+            .collect();
+        assert_eq!(
+            conversation,
+            [
+                (
+                    MessageRole::User,
+                    "New task:\nWhat is the capital of this country?".to_string(),
+                    "https://example.com/france.png".to_string(),
+                ),
+                (MessageRole::Assistant, "Paris.".to_string(), String::new()),
+                // The system message goes with the task
+                (
+                    MessageRole::User,
+                    "New task:\nBe brief.\n\nAnd of this one?".to_string(),
+                    "data:image/png;base64,aXRhbHk=".to_string(),
+                ),
+            ]
+        );
+
+        // The session of the request goes away with the response
+        let session_id = body["id"].as_str().unwrap();
+        for _ in 0..100 {
+            if agent.session_usage(session_id).await.is_none() {
+                return;
+            }
+            tokio::task::yield_now().await;
+        }
+        panic!("session {} was not forgotten", session_id);
+    }
+
+    #[tokio::test]
+    async fn unsupported_content_is_rejected() {
+        let model = ScriptedModel::new(vec![]);
+        let request = serde_json::from_value(json!({
+            "model": "agent-rs",
+            "messages": [{ "role": "user", "content": [
+                { "type": "text", "text": "Transcribe this." },
+                { "type": "input_audio", "input_audio": { "data": "", "format": "wav" } },
+            ] }],
+        }))
+        .unwrap();
+
+        let response = chat_completions(State(state(&model)), Json(request)).await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(model.inputs.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn timed_out_runs_are_errors() {
+        let model = ScriptedModel::hanging(vec![]);
+        let state = Arc::new(AppState {
+            agent: Arc::new(
+                Agent::new(model.clone(), 3, vec![], false)
+                    .with_run_timeout(Some(std::time::Duration::from_millis(50))),
+            ),
+            agent_name: "agent-rs".to_string(),
+        });
+        let request = serde_json::from_value(json!({
+            "model": "agent-rs",
+            "messages": [{ "role": "user", "content": "Take your time." }],
+        }))
+        .unwrap();
+
+        let response = chat_completions(State(state), Json(request)).await;
+
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["type"], "timeout");
+    }
+
+    #[tokio::test]
+    async fn streamed_errors_end_the_stream_without_finishing() {
+        let model = ScriptedModel::new(vec![
+            response("Let me look it up.", Some(("lookup", json!({}))), TokenUsage::new(10, 2)),
+            Err(ModelError::RateLimit("Slow down".to_string())),
+        ]);
+        let request = serde_json::from_value(json!({
+            "model": "agent-rs",
+            "messages": [{ "role": "user", "content": "What is the capital of Italy?" }],
+            "stream": true,
+        }))
+        .unwrap();
+
+        let response = chat_completions(State(state(&model)), Json(request)).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let data: Vec<_> = std::str::from_utf8(&body)
+            .unwrap()
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .collect();
+        assert_eq!(data.last(), Some(&"[DONE]"));
+        let error: serde_json::Value = serde_json::from_str(data[data.len() - 2]).unwrap();
+        assert_eq!(error["error"]["type"], "rate_limit");
+        assert!(!data.iter().any(|chunk| chunk.contains("\"finish_reason\":\"stop\"")), "{:#?}", data);
+    }
+}
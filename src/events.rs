@@ -0,0 +1,108 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::memory::{Timing, TokenUsage};
+use crate::models::ModelError;
+use crate::observation::Observation;
+
+/// Something that happened during a run, in the order it happened.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AgentEvent {
+    /// Text of a plan being written.
+    PlanDelta { text: String },
+    /// Text generated by the model while acting, or a notice from the agent.
+    TextDelta { text: String },
+    /// A tool is about to run. Code agents report each code block as a call to
+    /// `python_interpreter`.
+    ToolCallStarted {
+        step_number: usize,
+        id: String,
+        name: String,
+        arguments: HashMap<String, Value>,
+    },
+    /// What the tool call with the given id returned.
+    ToolResult {
+        step_number: usize,
+        id: String,
+        name: String,
+        observation: Observation,
+    },
+    /// The run asks its user a question and waits for the answer.
+    UserQuestion {
+        step_number: usize,
+        id: String,
+        question: String,
+    },
+    /// An action step ended, with the tokens it used and the error it ran into, if any.
+    StepFinished {
+        step_number: usize,
+        timing: Timing,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        usage: Option<TokenUsage>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    /// Answer of the run. `best_effort` answers were written after running out of steps, and
+    /// their text was already streamed as `TextDelta`s.
+    FinalAnswer { answer: String, best_effort: bool },
+    /// The model error that aborted the run; no event follows.
+    Error { error: ModelError },
+    /// The run was stopped before it could finish; no event follows. Its memory holds the steps
+    /// completed until then.
+    Cancelled { reason: CancelReason },
+}
+
+/// Why a run was cancelled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CancelReason {
+    /// At the request of the client, or because it went away.
+    Cancelled,
+    /// The run took longer than the agent allows.
+    TimedOut,
+}
+
+impl fmt::Display for CancelReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CancelReason::Cancelled => write!(f, "cancelled"),
+            CancelReason::TimedOut => write!(f, "timed out"),
+        }
+    }
+}
+
+impl AgentEvent {
+    /// Name of the event, as in its `type` field.
+    pub fn name(&self) -> &'static str {
+        match self {
+            AgentEvent::PlanDelta { .. } => "plan_delta",
+            AgentEvent::TextDelta { .. } => "text_delta",
+            AgentEvent::ToolCallStarted { .. } => "tool_call_started",
+            AgentEvent::ToolResult { .. } => "tool_result",
+            AgentEvent::UserQuestion { .. } => "user_question",
+            AgentEvent::StepFinished { .. } => "step_finished",
+            AgentEvent::FinalAnswer { .. } => "final_answer",
+            AgentEvent::Error { .. } => "error",
+            AgentEvent::Cancelled { .. } => "cancelled",
+        }
+    }
+
+    /// The event as plain text, as `/chat` has always streamed it. Events that were never part of
+    /// that output, and errors, have none.
+    pub fn text(&self) -> Option<String> {
+        match self {
+            AgentEvent::PlanDelta { text } | AgentEvent::TextDelta { text } => Some(text.clone()),
+            AgentEvent::ToolResult { observation, .. } if !observation.result.is_empty() => {
+                Some(format!("\nObservation:\n{}\n", observation.result))
+            }
+            AgentEvent::FinalAnswer { answer, best_effort: false } => {
+                Some(format!("\nFinal answer: {}\n", answer))
+            }
+            AgentEvent::Cancelled { reason } => Some(format!("\nRun {}.\n", reason)),
+            _ => None,
+        }
+    }
+}
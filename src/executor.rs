@@ -0,0 +1,376 @@
+use std::collections::HashMap;
+use std::io;
+use std::process::Stdio;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tempfile::TempDir;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tracing::info;
+
+use crate::actions::Action;
+use crate::observation::{Attachment, Observation};
+
+const DRIVER: &str = include_str!("../data/python_executor.py");
+
+/// Settings of the Python interpreter that runs code actions.
+#[derive(Clone, Debug)]
+pub struct PythonExecutorConfig {
+    /// Interpreter to launch.
+    pub python: String,
+    /// Modules the generated code may import, including their submodules. `"*"` allows any.
+    pub authorized_imports: Vec<String>,
+    /// Wall-clock time a code block may run between two tool calls.
+    pub timeout: Duration,
+    /// CPU time the interpreter may use over its whole life.
+    pub cpu_time_limit: Duration,
+    /// Address space the interpreter may use, in bytes.
+    pub memory_limit: u64,
+    /// Runs the interpreter in user, mount and network namespaces of its own, where it has no
+    /// network and sees only the system's libraries, read-only, and its working directory.
+    /// Needs unprivileged user namespaces, without which the interpreter fails to start; turned
+    /// off, only the in-process checks apply.
+    pub isolated: bool,
+}
+
+impl Default for PythonExecutorConfig {
+    fn default() -> Self {
+        Self {
+            python: "python3".to_string(),
+            authorized_imports: Vec::new(),
+            timeout: Duration::from_secs(30),
+            cpu_time_limit: Duration::from_secs(60),
+            memory_limit: 512 * 1024 * 1024,
+            isolated: true,
+        }
+    }
+}
+
+/// Result of running a code block to completion.
+#[derive(Clone, Debug)]
+pub struct ExecutionOutput {
+    /// Everything the code printed.
+    pub logs: String,
+    /// The value passed to the final answer tool if it was called, else the value of the
+    /// code's trailing expression, if any.
+    pub output: Option<Value>,
+    pub error: Option<String>,
+    pub is_final_answer: bool,
+    /// Files the code saved in its working directory, as data URIs: images are shown to the
+    /// model, other files are handed to the client.
+    pub attachments: Vec<Attachment>,
+}
+
+impl ExecutionOutput {
+    /// What the code showed: its printed output and the value of its trailing expression,
+    /// which is also kept as the observation's data.
+    pub fn observation(&self) -> Observation {
+        let mut parts = Vec::new();
+        if !self.logs.is_empty() {
+            parts.push(format!("Execution logs:\n{}", self.logs));
+        }
+        if let Some(output) = self.output.as_ref().filter(|_| !self.is_final_answer) {
+            let output = match output {
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            parts.push(format!("Last output from code snippet:\n{}", output));
+        }
+        let mut observation = match &self.error {
+            Some(_) => Observation::error(parts.join("\n")),
+            None => Observation::success(parts.join("\n")),
+        };
+        for attachment in &self.attachments {
+            observation = observation.with_attachment(attachment.clone());
+        }
+        match self.output.clone().filter(|_| !self.is_final_answer) {
+            Some(output) => observation.with_data(output),
+            None => observation,
+        }
+    }
+}
+
+/// What a running code block is waiting on.
+#[derive(Debug)]
+pub enum ExecutionEvent {
+    /// The code called a tool; answer with `PythonExecutor::send_tool_result`.
+    ToolCall {
+        name: String,
+        arguments: HashMap<String, Value>,
+    },
+    Finished(ExecutionOutput),
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum DriverMessage {
+    /// The sandbox is set up and the driver waits for code.
+    Started,
+    Failed {
+        error: String,
+    },
+    ToolCall {
+        name: String,
+        #[serde(default)]
+        arguments: HashMap<String, Value>,
+    },
+    Result {
+        logs: String,
+        output: Option<Value>,
+        error: Option<String>,
+        is_final_answer: bool,
+        #[serde(default)]
+        attachments: Vec<Attachment>,
+    },
+}
+
+/// A Python interpreter running in a child process, in a scratch working directory.
+/// Variables persist from one code block to the next; tools are exposed as functions.
+///
+/// The code is checked against the import allow-list before it runs, and the interpreter
+/// refuses network access, subprocesses, and file access outside of its working directory
+/// beyond reading the libraries it imports. Unless turned off, the interpreter is also isolated
+/// by the operating system (see `PythonExecutorConfig::isolated`), in case code gets past
+/// these checks.
+/// CPU and memory are capped with resource limits; a code block that runs past the timeout
+/// gets the interpreter killed.
+pub struct PythonExecutor {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+    /// Holds the working directory and the root of the isolated filesystem; removed along with
+    /// the executor.
+    _temp_dir: TempDir,
+    timeout: Duration,
+}
+
+impl PythonExecutor {
+    /// Starts an interpreter and waits until its sandbox is set up.
+    pub async fn spawn<'a>(
+        config: &PythonExecutorConfig,
+        tools: impl IntoIterator<Item = &'a dyn Action>,
+    ) -> io::Result<Self> {
+        let tools: Vec<Value> = tools
+            .into_iter()
+            .map(|tool| {
+                let info = tool.get_info();
+                let parameters: Vec<&str> = info.parameters.iter().map(|p| p.name.as_str()).collect();
+                json!({
+                    "name": info.name,
+                    "parameters": parameters,
+                    "final_answer": tool.is_final_answer(),
+                })
+            })
+            .collect();
+        let temp_dir = tempfile::Builder::new().prefix("agent-rs-").tempdir()?;
+        let work_dir = temp_dir.path().join("work");
+        let isolation_root = temp_dir.path().join("root");
+        std::fs::create_dir(&work_dir)?;
+        std::fs::create_dir(&isolation_root)?;
+        let driver_config = json!({
+            "tools": tools,
+            "authorized_imports": config.authorized_imports,
+            "cpu_time_limit": config.cpu_time_limit.as_secs().max(1),
+            "memory_limit": config.memory_limit,
+            "isolation_root": config.isolated.then_some(isolation_root),
+        });
+        let mut command = Command::new(&config.python);
+        // Isolated mode, without the server's environment
+        command.arg("-I").env_clear();
+        for name in ["PATH", "HOME", "LANG"] {
+            if let Some(value) = std::env::var_os(name) {
+                command.env(name, value);
+            }
+        }
+        let mut child = command
+            .arg("-c")
+            .arg(DRIVER)
+            .arg(serde_json::to_string(&driver_config)?)
+            .current_dir(&work_dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = BufReader::new(child.stdout.take().expect("stdout is piped")).lines();
+        let mut executor = Self {
+            child,
+            stdin,
+            stdout,
+            _temp_dir: temp_dir,
+            timeout: config.timeout,
+        };
+        match executor.receive_message().await? {
+            DriverMessage::Started => {
+                info!("Started Python executor in {}", work_dir.display());
+                Ok(executor)
+            }
+            DriverMessage::Failed { error } => Err(io::Error::other(error)),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected message from the Python interpreter")),
+        }
+    }
+
+    /// Starts running `code`. It runs until it calls a tool or finishes.
+    pub async fn execute(&mut self, code: &str) -> io::Result<ExecutionEvent> {
+        self.send(json!({ "type": "execute", "code": code })).await?;
+        self.receive().await
+    }
+
+    /// Hands the result of a tool call back to the code and resumes it.
+    pub async fn send_tool_result(&mut self, result: Result<String, String>) -> io::Result<ExecutionEvent> {
+        let message = match result {
+            Ok(result) => json!({ "type": "tool_result", "result": result, "error": null }),
+            Err(error) => json!({ "type": "tool_result", "result": null, "error": error }),
+        };
+        self.send(message).await?;
+        self.receive().await
+    }
+
+    async fn send(&mut self, message: Value) -> io::Result<()> {
+        let mut line = serde_json::to_string(&message)?;
+        line.push('\n');
+        self.stdin.write_all(line.as_bytes()).await?;
+        self.stdin.flush().await
+    }
+
+    async fn receive(&mut self) -> io::Result<ExecutionEvent> {
+        let event = match self.receive_message().await? {
+            DriverMessage::ToolCall { name, arguments } => ExecutionEvent::ToolCall { name, arguments },
+            DriverMessage::Result { logs, output, error, is_final_answer, attachments } => {
+                ExecutionEvent::Finished(ExecutionOutput { logs, output, error, is_final_answer, attachments })
+            }
+            DriverMessage::Started | DriverMessage::Failed { .. } => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected message from the Python interpreter"));
+            }
+        };
+        Ok(event)
+    }
+
+    async fn receive_message(&mut self) -> io::Result<DriverMessage> {
+        let Ok(line) = tokio::time::timeout(self.timeout, self.stdout.next_line()).await else {
+            self.child.kill().await?;
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("Code execution timed out after {:?}", self.timeout),
+            ));
+        };
+        let Some(line) = line? else {
+            let status = self.child.wait().await?;
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("Python interpreter exited unexpectedly ({})", status),
+            ));
+        };
+        Ok(serde_json::from_str(&line)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::observation::AttachmentKind;
+
+    async fn spawn(authorized_imports: &[&str]) -> PythonExecutor {
+        let config = PythonExecutorConfig {
+            authorized_imports: authorized_imports.iter().map(|module| module.to_string()).collect(),
+            ..Default::default()
+        };
+        PythonExecutor::spawn(&config, []).await.expect("start the Python interpreter")
+    }
+
+    async fn run(executor: &mut PythonExecutor, code: &str) -> ExecutionOutput {
+        match executor.execute(code).await.expect("run the code") {
+            ExecutionEvent::Finished(output) => output,
+            event => panic!("unexpected {:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn files_outside_the_work_dir_cannot_be_read() {
+        let mut executor = spawn(&["math", "json", "pathlib"]).await;
+        let manifest = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+        let output = run(&mut executor, &format!("print(open({:?}).read())", manifest)).await;
+        assert!(output.observation().is_error());
+        assert!(!output.logs.contains("[package]"));
+        let error = output.error.unwrap_or_default();
+        assert!(error.contains("PermissionError: Files can only be read inside the working directory"), "{}", error);
+
+        let output = run(&mut executor, "import pathlib\nlist(pathlib.Path('/').iterdir())").await;
+        assert!(output.observation().is_error());
+        let error = output.error.unwrap_or_default();
+        assert!(error.contains("PermissionError: Directories can only be listed"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn match_patterns_cannot_reach_the_driver() {
+        let mut executor = spawn(&["math", "json", "pathlib"]).await;
+        for code in [
+            "match final_answer:\n    case object(__globals__=g):\n        g['_AUTHORIZED_IMPORTS'] = ('*',)",
+            "match ():\n    case object(__class__=c):\n        pass",
+            "import json\nmatch json:\n    case object(sys=s):\n        pass",
+        ] {
+            let output = run(&mut executor, code).await;
+            let error = output.error.unwrap_or_default();
+            assert!(error.starts_with("PermissionError: Access to attribute"), "{}", error);
+        }
+        // Nothing changed: imports are still checked
+        let output = run(&mut executor, "import socket").await;
+        assert!(output.error.unwrap_or_default().starts_with("ImportError: Import of socket is not allowed"));
+    }
+
+    #[tokio::test]
+    async fn the_interpreter_is_isolated_from_the_system() {
+        // Authorizing every module lets the code past the in-process checks that `os.stat` and
+        // `os.path.exists` don't go through, as code escaping them would
+        let mut executor = spawn(&["*"]).await;
+        let manifest = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+        let code = format!(
+            "import os\n[os.path.exists(p) for p in ({:?}, '/etc/passwd', '/proc/self', os.getcwd())]",
+            manifest
+        );
+        let output = run(&mut executor, &code).await;
+        assert_eq!(output.error, None);
+        assert_eq!(output.output, Some(serde_json::json!([false, false, false, true])));
+
+        let code = "import os\nos.listdir('/')";
+        let output = run(&mut executor, code).await;
+        let error = output.error.unwrap_or_default();
+        assert!(error.contains("PermissionError: Directories can only be listed"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn work_dir_and_libraries_stay_usable() {
+        let mut executor = spawn(&["math", "json", "pathlib"]).await;
+        let code = "import math, json\nwith open('notes.txt', 'w') as f:\n    f.write(json.dumps(math.pi))\nopen('notes.txt').read()";
+        let output = run(&mut executor, code).await;
+        assert_eq!(output.error, None);
+        assert_eq!(output.output, Some(Value::String("3.141592653589793".to_string())));
+    }
+
+    #[tokio::test]
+    async fn saved_files_are_attached() {
+        let mut executor = spawn(&["math", "json", "pathlib"]).await;
+        let output = run(&mut executor, "with open('old.png', 'wb') as f:\n    f.write(b'old')").await;
+        assert_eq!(output.attachments.len(), 1);
+        assert_eq!(output.attachments[0].url, "data:image/png;base64,b2xk");
+
+        // Only the files saved by the latest code block are attached
+        let code = "with open('plot.jpg', 'wb') as f:\n    f.write(b'jpeg')\nwith open('table.csv', 'w') as f:\n    f.write('a,b')";
+        let observation = run(&mut executor, code).await.observation();
+        assert_eq!(observation.images(), ["data:image/jpeg;base64,anBlZw=="]);
+        let files: Vec<_> = observation
+            .attachments
+            .iter()
+            .map(|a| (a.kind, a.url.as_str(), a.mime_type.as_deref()))
+            .collect();
+        assert_eq!(
+            files,
+            [
+                (AttachmentKind::Image, "data:image/jpeg;base64,anBlZw==", Some("image/jpeg")),
+                (AttachmentKind::File, "data:text/csv;base64,YSxi", Some("text/csv")),
+            ]
+        );
+    }
+}
@@ -3,31 +3,37 @@
 use std::fs;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use futures::StreamExt;
-use std::convert::Infallible;
+use std::time::Duration;
+use futures::{Stream, StreamExt};
 
 use axum::{
-    extract::State,
-    http::StatusCode,
+    extract::{Path, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
     Json,
     response::{IntoResponse, Response},
+    response::sse::{Event, KeepAlive, Sse},
     Router,
-    routing::post,
+    routing::{get, post},
 };
 use axum::body::Body;
+use axum_streams::StreamBodyAs;
 use serde::Deserialize;
-use tracing::info;
-use tracing_subscriber;
+use tracing::{error, info};
 
-use models::{Model, OpenAIModel};
+use events::AgentEvent;
+use models::{ModelError, OpenAIModel};
 
 mod models;
-mod states;
 mod memory;
 mod actions;
 mod observation;
 mod agents;
 mod prompts;
+mod sessions;
+mod executor;
+mod events;
+mod completions;
+mod websocket;
 
 #[derive(Deserialize)]
 struct ServerConfig {
@@ -38,6 +44,14 @@ struct ServerConfig {
 #[derive(Deserialize)]
 struct RoutesConfig {
     chat: String,
+    usage: String,
+    /// OpenAI-compatible Chat Completions API.
+    completions: String,
+    models: String,
+    /// WebSocket sessions, with a `{session_id}` parameter.
+    websocket: String,
+    /// Cancels the run in progress in a session, with a `{session_id}` parameter.
+    cancel: String,
 }
 
 #[derive(Deserialize)]
@@ -46,11 +60,85 @@ struct ModelConfig {
     model_name: String,
 }
 
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+enum AgentKind {
+    /// Acts through tool calls.
+    #[default]
+    ToolCalling,
+    /// Acts by writing Python code.
+    Code,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct AgentConfig {
+    /// Model name under which the OpenAI-compatible API serves the agent.
+    name: String,
+    kind: AgentKind,
+    /// Steps between plan updates; `0` plans only once, and leaving it out disables planning.
+    planning_interval: Option<usize>,
+    /// Modules that code agents may import.
+    authorized_imports: Vec<String>,
+    /// Seconds after which runs are cancelled; leaving it out lets them run to the end.
+    run_timeout: Option<u64>,
+    /// Team members the agent can delegate tasks to.
+    managed_agents: Vec<ManagedAgentConfig>,
+    /// Appended to the system prompt of the agent.
+    instructions: Option<String>,
+    /// Seconds after which idle sessions are forgotten; `0` lifts the limit.
+    session_idle_timeout: u64,
+    /// Sessions kept at most, the least recently used going first; `0` lifts the limit.
+    max_sessions: usize,
+}
+
+/// An agent that works for the main agent, which calls it as a tool named `name`.
+#[derive(Deserialize)]
+struct ManagedAgentConfig {
+    name: String,
+    /// Tells the main agent what tasks to give it.
+    description: String,
+    #[serde(default)]
+    kind: AgentKind,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self {
+            name: "agent-rs".to_string(),
+            kind: AgentKind::default(),
+            planning_interval: None,
+            authorized_imports: Vec::new(),
+            run_timeout: None,
+            managed_agents: Vec::new(),
+            instructions: None,
+            session_idle_timeout: 3600,
+            max_sessions: 1000,
+        }
+    }
+}
+
+/// Settings of the search tools.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct SearchConfig {
+    /// Results a DuckDuckGo search returns at most.
+    max_results: Option<usize>,
+    /// Base URL of DuckDuckGo, e.g. to go through a proxy.
+    duckduckgo_url: Option<String>,
+    /// Base URL of the Naver Open API.
+    naver_url: Option<String>,
+}
+
 #[derive(Deserialize)]
 struct Config {
     server: ServerConfig,
     routes: RoutesConfig,
     model: ModelConfig,
+    #[serde(default)]
+    agent: AgentConfig,
+    #[serde(default)]
+    search: SearchConfig,
 }
 
 #[derive(Deserialize)]
@@ -77,11 +165,47 @@ struct ChatInput {
     name: String,
     query: String,
     stream: bool,
+    /// Clears the session's memory before running, starting a new conversation.
+    #[serde(default)]
+    reset: bool,
+    /// Format of the response; taken from the `Accept` header when left out.
+    format: Option<OutputFormat>,
+}
+
+/// How `/chat` writes out a run.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum OutputFormat {
+    /// The run's text, streamed or in one piece depending on `stream`.
+    Text,
+    /// Server-Sent Events named after the agent events, with JSON data.
+    Sse,
+    /// One JSON agent event per line.
+    Ndjson,
 }
 
+impl OutputFormat {
+    fn from_accept(headers: &HeaderMap) -> Self {
+        let accept = headers
+            .get(header::ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        if accept.contains("text/event-stream") {
+            OutputFormat::Sse
+        } else if accept.contains("application/x-ndjson") || accept.contains("application/jsonl") {
+            OutputFormat::Ndjson
+        } else {
+            OutputFormat::Text
+        }
+    }
+}
+
+/// Interval of the SSE comments that keep idle connections open, e.g. during long tool calls.
+const SSE_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
 struct AppState {
     agent: Arc<dyn agents::AgentBase + Send + Sync + 'static>,
-    model: Box<dyn Model + Send + Sync>,
+    agent_name: String,
 }
 
 #[tokio::main]
@@ -107,25 +231,73 @@ async fn main() {
 
     info!("Using OpenAI model: {} (type: {})", openai_model.model_name, config.model.model_type);
 
-    let agent = agents::Agent::new(
-        openai_model.clone(),
-        3,
-        vec![
-            Box::new(actions::DuckDuckGoSearchAction::new()),
-            Box::new(actions::NaverNewsSearchAction::new(
-                secrets.naver.client_id.clone(), secrets.naver.client_secret.clone()
-            ))
-        ],
-        true, // Enable streaming outputs
-    );
+    let build_agent = |kind: &AgentKind, actions: Vec<Box<dyn actions::Action>>| match kind {
+        AgentKind::ToolCalling => agents::Agent::new(
+            openai_model.clone(),
+            3,
+            actions,
+            true, // Enable streaming outputs
+        ),
+        AgentKind::Code => agents::Agent::new_code_agent(
+            openai_model.clone(),
+            3,
+            actions,
+            true, // Enable streaming outputs
+            executor::PythonExecutorConfig {
+                authorized_imports: config.agent.authorized_imports.clone(),
+                ..Default::default()
+            },
+        ),
+    };
+    let search_actions = || -> Vec<Box<dyn actions::Action>> {
+        let mut duckduckgo = actions::DuckDuckGoSearchAction::new();
+        if let Some(max_results) = config.search.max_results {
+            duckduckgo = duckduckgo.with_max_results(max_results);
+        }
+        if let Some(url) = &config.search.duckduckgo_url {
+            duckduckgo = duckduckgo.with_base_url(url);
+        }
+        let mut naver = actions::NaverNewsSearchAction::new(
+            secrets.naver.client_id.clone(), secrets.naver.client_secret.clone()
+        );
+        if let Some(url) = &config.search.naver_url {
+            naver = naver.with_base_url(url);
+        }
+        vec![Box::new(duckduckgo), Box::new(naver)]
+    };
+
+    let mut actions = search_actions();
+    // Only offered to runs with a user to answer, i.e. over the WebSocket
+    actions.push(Box::new(actions::AskUserAction::new()));
+    let mut agent = build_agent(&config.agent.kind, actions)
+        .with_planning_interval(config.agent.planning_interval)
+        .with_run_timeout(config.agent.run_timeout.map(Duration::from_secs))
+        .with_session_limits(
+            (config.agent.session_idle_timeout > 0).then(|| Duration::from_secs(config.agent.session_idle_timeout)),
+            (config.agent.max_sessions > 0).then_some(config.agent.max_sessions),
+        );
+    if let Some(instructions) = &config.agent.instructions {
+        agent = agent.with_custom_instructions(instructions.clone());
+    }
+    for managed in &config.agent.managed_agents {
+        info!("Adding managed agent: {}", managed.name);
+        let managed_agent = build_agent(&managed.kind, search_actions())
+            .into_managed_agent(managed.name.clone(), managed.description.clone());
+        agent = agent.with_managed_agent(managed_agent);
+    }
 
     let state = Arc::new(AppState {
         agent: Arc::new(agent) as Arc<dyn agents::AgentBase + Send + Sync + 'static>,
-        model: Box::new(openai_model),
+        agent_name: config.agent.name.clone(),
     });
 
     let app = Router::new()
         .route(&config.routes.chat, post(chat))
+        .route(&config.routes.usage, get(usage))
+        .route(&config.routes.completions, post(completions::chat_completions))
+        .route(&config.routes.models, get(completions::models))
+        .route(&config.routes.websocket, get(websocket::websocket))
+        .route(&config.routes.cancel, post(cancel))
         .with_state(state);
 
     let addr = format!("{}:{}", config.server.host, config.server.port)
@@ -143,21 +315,45 @@ async fn main() {
 
 async fn chat(
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
     Json(input): Json<ChatInput>,
-) -> Result<impl IntoResponse, (StatusCode, String)> {
+) -> Result<Response, (StatusCode, String)> {
 
     info!(
         "Processing chat: Session ID: {}, Chat ID: {}, Name: {}",
         input.session_id, input.chat_id, input.name
     );
     let start_time = std::time::Instant::now();
-    // Execute the agent, which yields a stream of text chunks
+    let format = input.format.unwrap_or_else(|| OutputFormat::from_accept(&headers));
+    // Execute the agent, which yields a stream of events
     let query = input.query.clone();
-    let mut stream = state.agent.clone().run(query, true).await;
+    let events = state
+        .agent
+        .clone()
+        .run(input.session_id.clone(), query, vec![], input.reset, None)
+        .await;
 
+    // A failure after the run has started ends the stream
+    let events = agents::started(events).await.map_err(|err| {
+        error!("Chat failed for session {}: {}", input.session_id, err);
+        (err.status_code(), err.to_string())
+    })?;
+    match format {
+        OutputFormat::Sse => return Ok(sse_response(events)),
+        OutputFormat::Ndjson => {
+            let content_type = HeaderValue::from_static("application/x-ndjson");
+            let response = StreamBodyAs::json_nl(events)
+                .headers(HeaderMap::from_iter([(header::CONTENT_TYPE, content_type)]))
+                .into_response();
+            return Ok(response);
+        }
+        OutputFormat::Text => {}
+    }
+
+    let mut stream = text_chunks(events);
     if input.stream {
-        // Stream chunks directly as SSE-like plain text
-        let byte_stream = stream.map(|chunk| Ok::<_, Infallible>(chunk.into_bytes()));
+        // Stream chunks directly as plain text; an error aborts the response body
+        let byte_stream = stream.map(|chunk| chunk.map(String::into_bytes));
         let response = Response::builder()
             .header("Content-Type", "text/plain")
             .body(Body::from_stream(byte_stream))
@@ -168,6 +364,10 @@ async fn chat(
     // Otherwise, accumulate all chunks into a full text response
     let mut full_text = String::new();
     while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|err| {
+            error!("Chat failed for session {}: {}", input.session_id, err);
+            (err.status_code(), err.to_string())
+        })?;
         full_text.push_str(&chunk);
     }
     let response = Response::builder()
@@ -182,3 +382,177 @@ async fn chat(
 
     Ok(response)
 }
+
+/// Streams `events` as Server-Sent Events numbered from 0, with keep-alive comments in between.
+fn sse_response(events: impl Stream<Item = AgentEvent> + Send + 'static) -> Response {
+    let events = events.enumerate().map(|(id, event)| {
+        Event::default()
+            .event(event.name())
+            .id(id.to_string())
+            .json_data(&event)
+    });
+    Sse::new(events)
+        .keep_alive(KeepAlive::new().interval(SSE_KEEP_ALIVE_INTERVAL))
+        .into_response()
+}
+
+/// The plain-text output of a run, ending early with the model error that aborted it, if any.
+fn text_chunks(
+    events: impl Stream<Item = AgentEvent> + Send + 'static,
+) -> impl Stream<Item = Result<String, ModelError>> + Unpin {
+    events.filter_map(|event| async move {
+        match event {
+            AgentEvent::Error { error } => Some(Err(error)),
+            event => event.text().map(Ok),
+        }
+    })
+    .boxed()
+}
+
+async fn cancel(
+    State(state): State<Arc<AppState>>,
+    Path(session_id): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if state.agent.cancel(&session_id).await {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err((StatusCode::NOT_FOUND, format!("No run in progress in session: {}", session_id)))
+    }
+}
+
+async fn usage(
+    State(state): State<Arc<AppState>>,
+    Path(session_id): Path<String>,
+) -> Result<Json<agents::SessionUsage>, (StatusCode, String)> {
+    state
+        .agent
+        .session_usage(&session_id)
+        .await
+        .map(Json)
+        .ok_or((StatusCode::NOT_FOUND, format!("Unknown session: {}", session_id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agents::tests::{response, ScriptedModel};
+    use crate::agents::Agent;
+    use crate::memory::TokenUsage;
+    use serde_json::json;
+
+    fn state(model: &ScriptedModel) -> Arc<AppState> {
+        Arc::new(AppState {
+            agent: Arc::new(Agent::new(model.clone(), 3, vec![], false)),
+            agent_name: "agent-rs".to_string(),
+        })
+    }
+
+    fn answering(answer: &str) -> ScriptedModel {
+        ScriptedModel::new(vec![response(
+            "Looking it up.",
+            Some(("final_answer", json!({ "answer": answer }))),
+            TokenUsage::new(10, 2),
+        )])
+    }
+
+    fn input(stream: bool, format: Option<&str>) -> Json<ChatInput> {
+        Json(
+            serde_json::from_value(json!({
+                "session_id": "session",
+                "chat_id": "chat",
+                "name": "user",
+                "query": "What is the capital of France?",
+                "stream": stream,
+                "format": format,
+            }))
+            .unwrap(),
+        )
+    }
+
+    fn accept(value: &'static str) -> HeaderMap {
+        HeaderMap::from_iter([(header::ACCEPT, HeaderValue::from_static(value))])
+    }
+
+    /// The content type and body of a response.
+    async fn read(response: Response) -> (String, String) {
+        let content_type = response.headers()[header::CONTENT_TYPE].to_str().unwrap().to_string();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (content_type, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn output_format_follows_the_accept_header() {
+        let cases = [
+            ("text/event-stream", OutputFormat::Sse),
+            ("application/x-ndjson", OutputFormat::Ndjson),
+            ("application/jsonl", OutputFormat::Ndjson),
+            ("text/plain", OutputFormat::Text),
+            ("*/*", OutputFormat::Text),
+        ];
+        for (value, format) in cases {
+            assert!(OutputFormat::from_accept(&accept(value)) == format, "{}", value);
+        }
+        assert!(OutputFormat::from_accept(&HeaderMap::new()) == OutputFormat::Text);
+    }
+
+    #[tokio::test]
+    async fn sse_events_are_named_and_numbered() {
+        let model = answering("Paris");
+        let response = chat(State(state(&model)), accept("text/event-stream"), input(true, None)).await;
+
+        let (content_type, body) = read(response.ok().unwrap()).await;
+        assert_eq!(content_type, "text/event-stream");
+        let events: Vec<_> = body.split("\n\n").filter(|event| !event.is_empty()).collect();
+        let field = |event: &str, name: &str| {
+            event
+                .lines()
+                .find_map(|line| line.strip_prefix(&format!("{}: ", name)).map(str::to_string))
+                .unwrap()
+        };
+        for (id, event) in events.iter().enumerate() {
+            assert_eq!(field(event, "id"), id.to_string());
+            let data: serde_json::Value = serde_json::from_str(&field(event, "data")).unwrap();
+            assert_eq!(data["type"], field(event, "event"));
+        }
+        let last = events.last().unwrap();
+        assert_eq!(field(last, "event"), "final_answer");
+        assert!(field(last, "data").contains("\"answer\":\"Paris\""), "{}", last);
+    }
+
+    #[tokio::test]
+    async fn format_overrides_the_accept_header() {
+        let model = answering("Paris");
+        let response = chat(State(state(&model)), accept("text/event-stream"), input(true, Some("ndjson"))).await;
+
+        let (content_type, body) = read(response.ok().unwrap()).await;
+        assert_eq!(content_type, "application/x-ndjson");
+        let events: Vec<serde_json::Value> = body.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(events.first().unwrap()["type"], "text_delta");
+        assert_eq!(events.last().unwrap()["type"], "final_answer");
+        assert_eq!(events.last().unwrap()["answer"], "Paris");
+    }
+
+    #[tokio::test]
+    async fn text_is_the_same_streamed_or_not() {
+        let mut bodies = vec![];
+        for stream in [true, false] {
+            let model = answering("Paris");
+            let response = chat(State(state(&model)), HeaderMap::new(), input(stream, None)).await;
+            let (content_type, body) = read(response.ok().unwrap()).await;
+            assert_eq!(content_type, "text/plain");
+            bodies.push(body);
+        }
+        assert_eq!(bodies[0], "Looking it up.\nFinal answer: Paris\n");
+        assert_eq!(bodies[0], bodies[1]);
+    }
+
+    #[tokio::test]
+    async fn runs_failing_at_once_get_an_error_status() {
+        let model = ScriptedModel::new(vec![Err(ModelError::RateLimit("Slow down".to_string()))]);
+        let response = chat(State(state(&model)), accept("text/event-stream"), input(true, None)).await;
+
+        let (status, message) = response.err().unwrap();
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert!(message.contains("Slow down"), "{}", message);
+    }
+}
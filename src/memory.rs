@@ -1,81 +1,80 @@
-use serde_json::{Map, Value};
-use async_openai::{
-    types::{
-        ChatCompletionRequestMessage,
-        ChatCompletionRequestUserMessageArgs,
-        ChatCompletionRequestAssistantMessageArgs,
-        ChatCompletionRequestToolMessageArgs,
-    }
-};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use tracing::info;
+use crate::models::ChatMessage;
+use crate::observation::Observation;
 use std::{
     fmt,
-    any::{Any, TypeId},
-    collections::HashMap
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-trait ToolBase {
-    fn dict(&self) -> HashMap<String, Value>;
-}
-
 trait TimeBase {
-    fn duration(&self) -> i32;
+    fn duration(&self) -> f64;
 }
 
-trait MemoryStep: Any {
-    fn dict(&self) -> HashMap<String, Value>;
-    fn to_message(&self, summary_mode: bool) -> Vec<ChatCompletionRequestMessage>;
-    fn as_any(&self) -> &dyn Any;
+pub trait MemoryStep {
+    fn to_message(&self, summary_mode: bool) -> Vec<ChatMessage>;
 }
 
 pub trait AgentMemoryBase {
     fn reset(&mut self);
-    fn get_succinct_steps(&self) -> Vec<Value>;
-    fn get_full_steps(&self) -> Vec<Value>;
-    fn replay(&self);
-    fn return_full_code(&self) -> String;
+    fn write_memory_to_messages(&self, summary_mode: bool) -> Vec<ChatMessage>;
+    /// Tokens used since the memory was last reset.
+    fn get_token_usage(&self) -> TokenUsage;
+    /// Tokens used by the latest run, i.e. since the last task was given.
+    fn get_run_token_usage(&self) -> TokenUsage;
 }
 
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ToolCall {
     pub id: String,
     pub name: String,
     pub arguments: HashMap<String, Value>,
+    /// Why the arguments written by the model could not be read, leaving `arguments` empty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arguments_error: Option<String>,
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct TokenUsage {
     pub prompt_tokens: usize,
     pub completion_tokens: usize,
     pub total_tokens: usize,
 }
 
+/// Wall-clock timing of a step, in seconds since the UNIX epoch.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct Timing {
-    pub start_time: i32,
-    pub end_time: i32,
+    pub start_time: f64,
+    pub end_time: f64,
 }
 
 pub struct ActionStep {
     pub step_number: usize,
     pub timing: Timing,
-    pub model_input_messages: Option<Vec<ChatCompletionRequestMessage>>,
     pub tool_calls: Option<Vec<ToolCall>>,
     pub error: Option<String>,
-    pub model_output_message: Option<ChatCompletionRequestMessage>,
+    pub model_output_message: Option<ChatMessage>,
     pub model_output: Option<String>,
     pub code_action: Option<String>,
     pub observations: Option<String>,
     pub observations_images: Option<Vec<String>>,
+    /// The observations behind `observations`, with their status, data and timing.
+    pub structured_observations: Option<Vec<Observation>>,
     pub action_output: Option<Value>,
     pub token_usage: Option<TokenUsage>,
     pub is_final_answer: bool,
 }
 
 pub struct PlanningStep {
-    model_input_messages: Vec<ChatCompletionRequestMessage>,
-    model_output_message: Option<ChatCompletionRequestMessage>,
-    plan: String,
-    timing: Timing,
-    token_usage: Option<TokenUsage>,
+    pub model_output_message: Option<ChatMessage>,
+    pub plan: String,
+    pub timing: Timing,
+    pub token_usage: Option<TokenUsage>,
+    /// Set when the plan could not be generated.
+    pub error: Option<String>,
 }
 
 pub struct TaskStep {
@@ -95,6 +94,7 @@ pub enum Step {
     Task(TaskStep),
     Action(ActionStep),
     Planning(PlanningStep),
+    FinalAnswer(FinalAnswerStep),
 }
 
 pub struct AgentMemory {
@@ -102,14 +102,56 @@ pub struct AgentMemory {
     pub steps: Vec<Step>,
 }
 
-type Callback = Box<dyn Fn(&dyn MemoryStep)>;
+impl Timing {
+    pub fn start() -> Self {
+        let now = Self::now();
+        Self {
+            start_time: now,
+            end_time: now,
+        }
+    }
+
+    pub fn finish(&mut self) {
+        self.end_time = Self::now();
+    }
+
+    fn now() -> f64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default()
+    }
+}
+
+impl TokenUsage {
+    pub fn new(prompt_tokens: usize, completion_tokens: usize) -> Self {
+        Self {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+}
+
+impl std::ops::AddAssign for TokenUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+    }
+}
 
-pub struct CallbackRegistry {
-    callbacks: HashMap<TypeId, Vec<Callback>>,
+impl std::iter::Sum for TokenUsage {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::default(), |mut total, usage| {
+            total += usage;
+            total
+        })
+    }
 }
 
 impl TimeBase for Timing {
-    fn duration(&self) -> i32 {
+    fn duration(&self) -> f64 {
         self.end_time - self.start_time
     }
 }
@@ -126,441 +168,224 @@ impl fmt::Display for Timing {
     }
 }
 
-impl ToolBase for ToolCall {
-    fn dict(&self) -> HashMap<String, Value> {
-        let mut output = HashMap::new();
-        output.insert("id".to_string(), Value::String(self.id.clone()));
-        output.insert("type".to_string(), Value::String("function".into()));
-        let mut func_obj = Map::new();
-        func_obj.insert("name".to_string(), Value::String(self.name.clone()));
-        func_obj.insert(
-            "arguments".to_string(),
-            Value::Object(
-                Map::from_iter(self.arguments.iter().map(|(k, v)| (k.clone(), v.clone())))
-            ),
-        );
-        output.insert("function".to_string(), Value::Object(func_obj));
-        output
-    }
-}
-
-impl ToolBase for Timing {
-    fn dict(&self) -> HashMap<String, Value> {
-        let mut output = HashMap::new();
-        output.insert("start_time".to_string(), Value::Number(self.start_time.into()));
-        output.insert("end_time".to_string(), Value::Number(self.end_time.into()));
-        output.insert("duration".to_string(), Value::Number(self.duration().into()));
-        output
-    }
-}
-
 impl MemoryStep for ActionStep {
-    fn dict(&self) -> HashMap<String, Value> {
-        let mut output = HashMap::new();
-
-        output.insert(
-            "step_number".to_string(),
-            Value::Number(self.step_number.into()),
-        );
-        output.insert(
-            "timing".to_string(),
-            Value::Object(Map::from_iter(self.timing.dict().into_iter())),
-        );
-        output.insert(
-            "model_input_messages".to_string(),
-            self.model_input_messages.as_ref().map_or(Value::Null, |msgs| {
-                Value::Array(
-                    msgs.iter()
-                        .map(|msg| serde_json::to_value(msg).expect("serialize msg"))
-                        .collect(),
-                )
-            }),
-        );
-        output.insert(
-            "tool_calls".to_string(),
-            self.tool_calls.as_ref().map_or(Value::Null, |calls| {
-                Value::Array(
-                    calls.iter()
-                        .map(|call| Value::Object(Map::from_iter(call.dict().into_iter())))
-                        .collect(),
-                )
-            }),
-        );
-        output.insert(
-            "error".to_string(),
-            self.error.clone().map_or(Value::Null, Value::String),
-        );
-        output.insert(
-            "model_output_message".to_string(),
-            self.model_output_message
-                .as_ref()
-                .map_or(Value::Null, |msg| {
-                    serde_json::to_value(msg).expect("serialize model_output_message")
-                }),
-        );
-        output.insert(
-            "model_output".to_string(),
-            self.model_output.clone().map_or(Value::Null, Value::String),
-        );
-        output.insert(
-            "code_action".to_string(),
-            self.code_action.clone().map_or(Value::Null, Value::String),
-        );
-        output.insert(
-            "observations".to_string(),
-            self.observations.clone().map_or(Value::Null, Value::String),
-        );
-        output.insert(
-            "observations_images".to_string(),
-            self.observations_images.as_ref().map_or(Value::Null, |images| {
-                Value::Array(images.iter().map(|s| Value::String(s.clone())).collect())
-            }),
-        );
-        output.insert(
-            "action_output".to_string(),
-            self.action_output.clone().map_or(Value::Null, |v| v),
-        );
-        output.insert(
-            "token_usage".to_string(),
-            self.token_usage.as_ref().map_or(Value::Null, |usage| {
-                let mut usage_map = Map::new();
-                usage_map.insert("prompt_tokens".to_string(), Value::Number(usage.prompt_tokens.into()));
-                usage_map.insert("completion_tokens".to_string(), Value::Number(usage.completion_tokens.into()));
-                usage_map.insert("total_tokens".to_string(), Value::Number(usage.total_tokens.into()));
-                Value::Object(usage_map)
-            }),
-        );
-        output.insert(
-            "is_final_answer".to_string(),
-            Value::Bool(self.is_final_answer),
-        );
-
-        output
-    }
-
-    fn to_message(&self, summary_mode: bool) -> Vec<ChatCompletionRequestMessage> {
+    fn to_message(&self, summary_mode: bool) -> Vec<ChatMessage> {
         let mut messages = Vec::new();
-        if let Some(output) = &self.model_output {
-            if !summary_mode {
-                messages.push(
-                    ChatCompletionRequestAssistantMessageArgs::default()
-                        .content(output.clone())
-                        .build()
-                        .expect("Failed to build assistant message")
-                        .into(),
-                );
-            }
+        let output = match &self.model_output {
+            Some(output) if !summary_mode => output.clone(),
+            _ => String::new(),
+        };
+        let calls = self.tool_calls.clone().unwrap_or_default();
+        if !output.is_empty() || !calls.is_empty() {
+            messages.push(ChatMessage::assistant(output).with_tool_calls(calls.clone()));
         }
 
-        if let Some(calls) = &self.tool_calls {
-            for call in calls {
-                messages.push(
-                    ChatCompletionRequestToolMessageArgs::default()
-                        .content(format!(
-                            "Calling tools:\n{}",
-                            serde_json::to_string(&call.dict()).unwrap_or_default()
-                        ))
-                        .build()
-                        .expect("Failed to build tool message")
-                        .into(),
-                );
+        // Every tool call has to be answered by a tool message carrying its id, with its own result
+        if calls.is_empty() {
+            let mut result = Vec::new();
+            if let Some(obs) = &self.observations {
+                result.push(format!("Observations:\n{}", obs));
             }
-        }
-
-        if let Some(images) = &self.observations_images {
-            for img in images {
-                messages.push(
-                    ChatCompletionRequestUserMessageArgs::default()
-                        .content(format!("Observation image: {}", img))
-                        .build()
-                        .expect("Failed to build user message for image")
-                        .into(),
-                );
+            if let Some(err) = &self.error {
+                result.push(error_message(err));
+            }
+            if !result.is_empty() {
+                messages.push(ChatMessage::user(result.join("\n")));
+            }
+        } else {
+            let observations = self.structured_observations.as_deref().unwrap_or_default();
+            for call in &calls {
+                let observation = observations
+                    .iter()
+                    .find(|observation| observation.tool_call_id.as_ref() == Some(&call.id));
+                let content = match observation {
+                    Some(observation) if observation.is_error() => error_message(&observation.result),
+                    Some(observation) => format!("Observations:\n{}", observation.result),
+                    None => "No observations.".to_string(),
+                };
+                messages.push(ChatMessage::tool(call.id.clone(), content));
             }
         }
 
-        if let Some(obs) = &self.observations {
-            messages.push(
-                ChatCompletionRequestToolMessageArgs::default()
-                    .content(format!("Observations:\n{}", obs))
-                    .build()
-                    .expect("Failed to build tool message for observations")
-                    .into(),
-            );
-        }
-
-        if let Some(err) = &self.error {
-            let call_id = self
-                .tool_calls
-                .as_ref()
-                .and_then(|calls| calls.first().map(|c| c.id.clone()))
-                .unwrap_or_else(|| "None".to_string());
-            let error_msg = format!(
-                "Error occurred: {}\nNow let's retry: take care not to repeat previous errors! If you have retried several times, try a completely different approach.\n",
-                err
-            );
-            messages.push(
-                ChatCompletionRequestToolMessageArgs::default()
-                    .content(format!("Call id: {}\n{}", call_id, error_msg))
-                    .build()
-                    .expect("Failed to build tool message for error")
-                    .into(),
+        if let Some(images) = &self.observations_images {
+            let message = images.iter().fold(
+                ChatMessage::user("Here are the observed images:"),
+                |message, img| message.with_image(img.clone()),
             );
+            messages.push(message);
         }
 
         messages
     }
 
-    fn as_any(&self) -> &dyn Any {
-        self
-    }
 }
 
 
-impl MemoryStep for PlanningStep {
-    fn dict(&self) -> HashMap<String, Value> {
-        let mut output = HashMap::new();
-        output.insert(
-            "model_input_messages".to_string(),
-            Value::Array(
-                self.model_input_messages
-                    .iter()
-                    .map(|msg| serde_json::to_value(msg).expect("serialize msg"))
-                    .collect(),
-            ),
-        );
-        output.insert(
-            "model_output_message".to_string(),
-            self.model_output_message
-                .as_ref()
-                .map_or(Value::Null, |msg| {
-                    serde_json::to_value(msg).expect("serialize model_output_message")
-                }),
-        );
-        output.insert("plan".to_string(), Value::String(self.plan.clone()));
-        output.insert(
-            "timing".to_string(),
-            Value::Object(Map::from_iter(self.timing.dict().into_iter())),
-        );
-        output.insert(
-            "token_usage".to_string(),
-            self.token_usage.as_ref().map_or(Value::Null, |usage| {
-                let mut usage_map = Map::new();
-                usage_map.insert("prompt_tokens".to_string(), Value::Number(usage.prompt_tokens.into()));
-                usage_map.insert("completion_tokens".to_string(), Value::Number(usage.completion_tokens.into()));
-                usage_map.insert("total_tokens".to_string(), Value::Number(usage.total_tokens.into()));
-                Value::Object(usage_map)
-            }),
-        );
-
-        output
-    }
+/// Reports an error to the model, asking it to retry.
+fn error_message(err: &str) -> String {
+    format!(
+        "Error occurred: {}\nNow let's retry: take care not to repeat previous errors! If you have retried several times, try a completely different approach.\n",
+        err
+    )
+}
 
-    fn to_message(&self, summary_mode: bool) -> Vec<ChatCompletionRequestMessage> {
-        if summary_mode {
+impl MemoryStep for PlanningStep {
+    fn to_message(&self, summary_mode: bool) -> Vec<ChatMessage> {
+        // A failed planning attempt has no plan to carry out
+        if summary_mode || self.error.is_some() {
             vec![]
         } else {
-            let mut messages = Vec::new();
-            messages.push(
-                ChatCompletionRequestAssistantMessageArgs::default()
-                    .content(self.plan.clone())
-                    .build()
-                    .expect("Failed to build assistant message")
-                    .into(),
-            );
-            messages.push(
-                ChatCompletionRequestUserMessageArgs::default()
-                    .content("Now proceed and carry out this plan.".to_string())
-                    .build()
-                    .expect("Failed to build user message")
-                    .into(),
-            );
-            messages
+            vec![
+                ChatMessage::assistant(self.plan.clone()),
+                ChatMessage::user("Now proceed and carry out this plan."),
+            ]
         }
     }
 
-    fn as_any(&self) -> &dyn Any {
-        self
-    }
 }
 
 impl MemoryStep for TaskStep {
-    fn dict(&self) -> HashMap<String, Value> {
-        let mut output = HashMap::new();
-        output.insert("task".to_string(), Value::String(self.task.clone()));
-        output.insert(
-            "task_images".to_string(),
-            self.task_images.as_ref().map_or(Value::Null, |images| {
-                Value::Array(images.iter().map(|s| Value::String(s.clone())).collect())
-            }),
-        );
-        output
-    }
-
-    fn to_message(&self, summary_mode: bool) -> Vec<ChatCompletionRequestMessage> {
+    fn to_message(&self, summary_mode: bool) -> Vec<ChatMessage> {
         info!("TaskStep to_message called with summary_mode={}", summary_mode);
-        let mut messages = Vec::new();
-        messages.push(
-            ChatCompletionRequestUserMessageArgs::default()
-                .content(format!("New task:\n{}", self.task))
-                .build()
-                .expect("Failed to build user message for task")
-                .into(),
-        );
-        if let Some(images) = &self.task_images {
-            for img in images {
-                messages.push(
-                    ChatCompletionRequestUserMessageArgs::default()
-                        .content(format!("Task image: {}", img))
-                        .build()
-                        .expect("Failed to build user message for image")
-                        .into(),
-                );
-            }
+        let mut message = ChatMessage::user(format!("New task:\n{}", self.task));
+        for img in self.task_images.iter().flatten() {
+            message = message.with_image(img.clone());
         }
-
-        messages
+        vec![message]
     }
 
-    fn as_any(&self) -> &dyn Any {
-        self
-    }
 }
 
 impl MemoryStep for SystemPromptStep {
-    fn dict(&self) -> HashMap<String, Value> {
-        let mut output = HashMap::new();
-        output.insert("system_prompt".to_string(), Value::String(self.system_prompt.clone()));
-        output
-    }
-
-    fn to_message(&self, summary_mode: bool) -> Vec<ChatCompletionRequestMessage> {
+    fn to_message(&self, summary_mode: bool) -> Vec<ChatMessage> {
         if summary_mode {
             vec![]
         } else {
-            vec![ChatCompletionRequestUserMessageArgs::default()
-                .content(self.system_prompt.clone())
-                .build()
-                .expect("Failed to build user message for system prompt")
-                .into()]
+            vec![ChatMessage::system(self.system_prompt.clone())]
         }
     }
 
-    fn as_any(&self) -> &dyn Any {
-        self
-    }
 }
 
 impl MemoryStep for FinalAnswerStep {
-    fn dict(&self) -> HashMap<String, Value> {
-        let mut output = HashMap::new();
-        output.insert("output".to_string(), Value::String(self.output.clone()));
-        output
-    }
-
-    fn to_message(&self, summary_mode: bool) -> Vec<ChatCompletionRequestMessage> {
+    fn to_message(&self, summary_mode: bool) -> Vec<ChatMessage> {
         if summary_mode {
             vec![]
         } else {
-            vec![ChatCompletionRequestUserMessageArgs::default()
-                .content(self.output.clone())
-                .build()
-                .expect("Failed to build user message for final answer")
-                .into()]
+            vec![ChatMessage::assistant(self.output.clone())]
         }
     }
 
-    fn as_any(&self) -> &dyn Any {
-        self
-    }
 }
 
 impl AgentMemoryBase for AgentMemory {
     fn reset(&mut self) {
         self.steps.clear();
     }
-    fn get_succinct_steps(&self) -> Vec<Value> {
-        self.steps.iter().map(|step| {
-            // 1) 원래 dict 생성
-            let mut data = match step {
-                Step::Task(ts)     => ts.dict(),
-                Step::Action(as_)  => as_.dict(),
-                Step::Planning(ps) => ps.dict(),
-            };
-            // 2) model_input_messages 키만 제거
-            data.remove("model_input_messages");
-            // 3) HashMap → serde_json::Map → Value::Object
-            Value::Object(Map::from_iter(data.into_iter()))
-        })
-            .collect()
-    }
-
-    fn get_full_steps(&self) -> Vec<Value> {
-        if self.steps.is_empty() {
-            vec![]
-        } else {
-            self.steps.iter().map(|step| {
-                match step {
-                    Step::Task(ts)     => ts.dict(),
-                    Step::Action(as_)  => as_.dict(),
-                    Step::Planning(ps) => ps.dict(),
-                }
-            })
-            .map(|data| Value::Object(Map::from_iter(data.into_iter())))
-            .collect()
+    fn write_memory_to_messages(&self, summary_mode: bool) -> Vec<ChatMessage> {
+        let mut messages = self.system_prompt.to_message(summary_mode);
+        for step in &self.steps {
+            messages.extend(step.to_message(summary_mode));
         }
+        messages
     }
 
-    fn replay(&self) {
-        todo!()
+    fn get_token_usage(&self) -> TokenUsage {
+        self.steps.iter().filter_map(Step::token_usage).sum()
     }
 
-    fn return_full_code(&self) -> String {
-        let full_code: Vec<String> = self.steps.iter()
-            .filter_map(|step| {
-                if let Step::Action(action_step) = step {
-                    action_step.code_action.clone()
-                } else {
-                    None
-                }
-            })
-            .collect();
-        full_code.join("\n\n")
+    fn get_run_token_usage(&self) -> TokenUsage {
+        self.steps
+            .iter()
+            .rev()
+            .take_while(|step| !matches!(step, Step::Task(_)))
+            .filter_map(Step::token_usage)
+            .sum()
     }
 }
 
-
-impl CallbackRegistry {
-    pub fn new() -> Self {
-        Self { callbacks: HashMap::new() }
+impl Step {
+    fn token_usage(&self) -> Option<TokenUsage> {
+        match self {
+            Step::Action(as_) => as_.token_usage,
+            Step::Planning(ps) => ps.token_usage,
+            Step::Task(_) | Step::FinalAnswer(_) => None,
+        }
     }
 
-    pub fn register<S, F>(&mut self, callback: F)
-    where
-        S: MemoryStep + 'static,
-        F: Fn(&S) + 'static,
-    {
-        // Box<dyn Fn(&dyn MemoryStep)> 형태로 래핑
-        let wrapped: Callback = Box::new(move |step: &dyn MemoryStep| {
-            // 실제로는 S 타입인지 downcast 후 호출
-            if let Some(s) = step.as_any().downcast_ref::<S>() {
-                callback(s);
-            }
-        });
+    fn to_message(&self, summary_mode: bool) -> Vec<ChatMessage> {
+        match self {
+            Step::Task(ts)     => ts.to_message(summary_mode),
+            Step::Action(as_)  => as_.to_message(summary_mode),
+            Step::Planning(ps) => ps.to_message(summary_mode),
+            Step::FinalAnswer(fs) => fs.to_message(summary_mode),
+        }
+    }
+}
 
-        self
-            .callbacks
-            .entry(TypeId::of::<S>())
-            .or_default()
-            .push(wrapped);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::MessageRole;
+
+    fn call(id: &str) -> ToolCall {
+        ToolCall {
+            id: id.to_string(),
+            name: "web_search".to_string(),
+            arguments: HashMap::new(),
+            arguments_error: None,
+        }
     }
 
-    pub fn callback(&self, memory_step: &dyn MemoryStep) {
-        let tid = memory_step.as_any().type_id();
-        if let Some(cbs) = self.callbacks.get(&tid) {
-            for cb in cbs {
-                cb(memory_step);
-            }
+    fn observation(id: &str, observation: Observation) -> Observation {
+        Observation {
+            tool_call_id: Some(id.to_string()),
+            ..observation
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn parallel_tool_calls_are_answered_with_their_own_result() {
+        let step = ActionStep {
+            step_number: 1,
+            timing: Timing::start(),
+            tool_calls: Some(vec![call("call_a"), call("call_b"), call("call_c")]),
+            error: Some("Invalid call to tool web_search: missing argument query.".to_string()),
+            model_output_message: None,
+            model_output: None,
+            code_action: None,
+            observations: Some("first\nsecond".to_string()),
+            observations_images: None,
+            structured_observations: Some(vec![
+                observation("call_b", Observation::success("second")),
+                observation("call_a", Observation::success("first")),
+            ]),
+            action_output: None,
+            token_usage: None,
+            is_final_answer: false,
+        };
+        let messages = step.to_message(false);
+        let results = messages
+            .iter()
+            .filter(|message| message.role == MessageRole::Tool)
+            .map(|message| (message.tool_call_id.clone().unwrap(), message.text()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            results,
+            vec![
+                ("call_a".to_string(), "Observations:\nfirst".to_string()),
+                ("call_b".to_string(), "Observations:\nsecond".to_string()),
+                ("call_c".to_string(), "No observations.".to_string()),
+            ]
+        );
+
+        let mut step = step;
+        step.structured_observations = Some(vec![observation(
+            "call_a",
+            Observation::error("Invalid call to tool web_search: missing argument query."),
+        )]);
+        let messages = step.to_message(false);
+        let first = messages.iter().find(|message| message.role == MessageRole::Tool).unwrap();
+        assert!(first.text().starts_with("Error occurred: Invalid call to tool web_search"));
+    }
+}
@@ -1,5 +1,5 @@
 use std::{
-    convert::Infallible,
+    fmt,
     pin::Pin,
     collections::HashMap
 };
@@ -7,42 +7,258 @@ use std::{
 use async_openai::{
     Client,
     config::OpenAIConfig,
+    error::OpenAIError,
     types::{
+        ChatCompletionMessageToolCall,
         ChatCompletionRequestMessage,
+        ChatCompletionRequestMessageContentPartImage,
+        ChatCompletionRequestMessageContentPartText,
         ChatCompletionRequestUserMessageArgs,
+        ChatCompletionRequestUserMessageContentPart,
         ChatCompletionRequestAssistantMessageArgs,
         ChatCompletionRequestSystemMessageArgs,
-        CreateChatCompletionRequestArgs
+        ChatCompletionRequestToolMessageArgs,
+        ChatCompletionStreamOptions,
+        ChatCompletionTool,
+        ChatCompletionToolArgs,
+        ChatCompletionToolType,
+        CreateChatCompletionRequestArgs,
+        FunctionCall,
+        FunctionObjectArgs,
+        ImageUrl,
     },
 };
+use async_stream::stream;
 use async_trait::async_trait;
 use axum::http::StatusCode;
-use bytes::Bytes;
-use futures::{stream::BoxStream, StreamExt};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::warn;
 
+use crate::actions::ToolSchema;
+use crate::memory::{TokenUsage, ToolCall};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageRole {
+    System,
+    User,
+    Assistant,
+    Tool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    /// An image given by URL or as a base64 `data:` URI.
+    Image { url: String },
+}
+
+/// A chat message exchanged with a model, independent of any backend's wire format.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: MessageRole,
+    pub content: Vec<ContentPart>,
+    /// Tools the assistant asked to call in this message.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// The tool call a `Tool` message is the result of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl ChatMessage {
+    pub fn new(role: MessageRole, text: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: vec![ContentPart::Text { text: text.into() }],
+            tool_calls: vec![],
+            tool_call_id: None,
+        }
+    }
+
+    pub fn system(text: impl Into<String>) -> Self {
+        Self::new(MessageRole::System, text)
+    }
+
+    pub fn user(text: impl Into<String>) -> Self {
+        Self::new(MessageRole::User, text)
+    }
+
+    pub fn assistant(text: impl Into<String>) -> Self {
+        Self::new(MessageRole::Assistant, text)
+    }
+
+    pub fn tool(tool_call_id: impl Into<String>, text: impl Into<String>) -> Self {
+        Self {
+            tool_call_id: Some(tool_call_id.into()),
+            ..Self::new(MessageRole::Tool, text)
+        }
+    }
+
+    pub fn with_image(mut self, url: impl Into<String>) -> Self {
+        self.content.push(ContentPart::Image { url: url.into() });
+        self
+    }
+
+    pub fn with_tool_calls(mut self, tool_calls: Vec<ToolCall>) -> Self {
+        self.tool_calls = tool_calls;
+        self
+    }
+
+    /// Concatenation of the text parts of the message.
+    pub fn text(&self) -> String {
+        self.content
+            .iter()
+            .filter_map(|part| match part {
+                ContentPart::Text { text } => Some(text.as_str()),
+                ContentPart::Image { .. } => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Failure of a model call, classified so that callers can react to it.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "kind", content = "message", rename_all = "snake_case")]
+pub enum ModelError {
+    /// The provider rejected our credentials.
+    Authentication(String),
+    /// Too many requests or an exhausted quota; retrying later may succeed.
+    RateLimit(String),
+    /// The conversation does not fit into the model's context window.
+    ContextLength(String),
+    /// The provider could not be reached or the connection dropped.
+    Network(String),
+    /// The request was malformed or rejected by the provider.
+    InvalidRequest(String),
+    /// Any other provider-side failure.
+    Api(String),
+}
+
+impl ModelError {
+    /// The status our own HTTP API answers with when a request fails because of this error.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ModelError::RateLimit(_) => StatusCode::TOO_MANY_REQUESTS,
+            ModelError::ContextLength(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ModelError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            ModelError::Authentication(_) | ModelError::Network(_) | ModelError::Api(_) => {
+                StatusCode::BAD_GATEWAY
+            }
+        }
+    }
+
+    /// Classifies an error from an HTTP status code and the message that came with it.
+    fn from_status(status: u16, message: String) -> Self {
+        match status {
+            401 | 403 => ModelError::Authentication(message),
+            429 => ModelError::RateLimit(message),
+            400 | 413 if message.contains("context_length") => ModelError::ContextLength(message),
+            400 | 404 | 413 | 422 => ModelError::InvalidRequest(message),
+            _ => ModelError::Api(message),
+        }
+    }
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModelError::Authentication(msg) => write!(f, "model authentication failed: {}", msg),
+            ModelError::RateLimit(msg) => write!(f, "model rate limit exceeded: {}", msg),
+            ModelError::ContextLength(msg) => write!(f, "model context length exceeded: {}", msg),
+            ModelError::Network(msg) => write!(f, "model unreachable: {}", msg),
+            ModelError::InvalidRequest(msg) => write!(f, "invalid model request: {}", msg),
+            ModelError::Api(msg) => write!(f, "model API error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ModelError {}
+
+impl From<OpenAIError> for ModelError {
+    fn from(err: OpenAIError) -> Self {
+        match err {
+            OpenAIError::ApiError(api) => {
+                let message = api.to_string();
+                match (api.code.as_deref(), api.r#type.as_deref()) {
+                    (Some("invalid_api_key"), _) | (_, Some("authentication_error")) => {
+                        ModelError::Authentication(message)
+                    }
+                    (Some("rate_limit_exceeded" | "insufficient_quota"), _) => ModelError::RateLimit(message),
+                    (Some("context_length_exceeded"), _) => ModelError::ContextLength(message),
+                    (_, Some("invalid_request_error")) => ModelError::InvalidRequest(message),
+                    _ => ModelError::Api(message),
+                }
+            }
+            OpenAIError::Reqwest(e) => match e.status() {
+                Some(status) => ModelError::from_status(status.as_u16(), e.to_string()),
+                None => ModelError::Network(e.to_string()),
+            },
+            // SSE failures only carry a message, e.g. "Invalid status code: 429 Too Many Requests"
+            OpenAIError::StreamError(message) => {
+                let status = message
+                    .strip_prefix("Invalid status code: ")
+                    .and_then(|rest| rest.split_whitespace().next())
+                    .and_then(|code| code.parse::<u16>().ok());
+                match status {
+                    Some(status) => ModelError::from_status(status, message),
+                    None => ModelError::Network(message),
+                }
+            }
+            OpenAIError::InvalidArgument(message) => ModelError::InvalidRequest(message),
+            other => ModelError::Api(other.to_string()),
+        }
+    }
+}
+
+/// A piece of a streamed model response.
+pub enum ModelDelta {
+    Text(String),
+    /// Tool calls requested by the model, assembled from their streamed fragments.
+    ToolCalls(Vec<ToolCall>),
+    /// Tokens consumed by the request, reported once the response is complete.
+    Usage(TokenUsage),
+}
+
+/// A complete model response.
+#[derive(Default)]
+pub struct ModelResponse {
+    pub content: String,
+    pub tool_calls: Vec<ToolCall>,
+    pub token_usage: Option<TokenUsage>,
+}
 
 #[async_trait]
 pub trait Model: Send + Sync {
     async fn async_generate_stream(
         &self,
-        messages: Vec<HashMap<String, String>>,
+        messages: Vec<ChatMessage>,
+        tools: Vec<ToolSchema>,
     ) -> Result<
-        Pin<Box<dyn futures::Stream<Item=Result<Bytes, Infallible>> + Send>>,
-        (StatusCode, String),
+        Pin<Box<dyn futures::Stream<Item=Result<ModelDelta, ModelError>> + Send>>,
+        ModelError,
     >;
 
-    async fn async_generate(&self, messages:Vec<HashMap<String, String>>) -> String {
-        let stream = self.async_generate_stream(messages)
-            .await
-            .expect("Failed to generate stream");
+    async fn async_generate(
+        &self,
+        messages: Vec<ChatMessage>,
+        tools: Vec<ToolSchema>,
+    ) -> Result<ModelResponse, ModelError> {
+        let mut stream = self.async_generate_stream(messages, tools).await?;
 
-        let chunks = stream.collect::<Vec<_>>().await;
-        let mut output = String::new();
-        for chunk in chunks {
-            let bytes = chunk.expect("Failed to get chunk");
-            output.push_str(&String::from_utf8_lossy(&bytes));
+        let mut output = ModelResponse::default();
+        while let Some(chunk) = stream.next().await {
+            match chunk? {
+                ModelDelta::Text(text) => output.content.push_str(&text),
+                ModelDelta::ToolCalls(calls) => output.tool_calls.extend(calls),
+                ModelDelta::Usage(usage) => output.token_usage = Some(usage),
+            }
         }
-        output
+        Ok(output)
     }
 
 }
@@ -75,47 +291,126 @@ impl OpenAIModel {
         }
     }
 
-    fn prepare_inputs(&self, inputs: Vec<HashMap<String, String>>) -> Vec<ChatCompletionRequestMessage> {
-        let mut outputs:Vec<ChatCompletionRequestMessage> = Vec::new();
+    fn prepare_inputs(&self, inputs: Vec<ChatMessage>) -> Result<Vec<ChatCompletionRequestMessage>, OpenAIError> {
+        let mut outputs: Vec<ChatCompletionRequestMessage> = Vec::new();
         for input in inputs {
-            let message = match input.get("role") {
-                Some(role) => {
-                    match role.as_str() {
-                        "user" => {
-                            ChatCompletionRequestUserMessageArgs::default()
-                                .content(input.get("content").unwrap_or(&"".to_string()).to_string())
-                                .build()
-                                .expect("Failed to build user message")
-                                .into()
-                        }
-                        "assistant" => {
-                            ChatCompletionRequestAssistantMessageArgs::default()
-                                .content(input.get("content").unwrap_or(&"".to_string()).to_string())
-                                .build()
-                                .expect("Failed to build assistant message")
-                                .into()
-                        }
-                        "system" => {
-                            ChatCompletionRequestSystemMessageArgs::default()
-                                .content(input.get("content").unwrap_or(&"".to_string()).to_string())
-                                .build()
-                                .expect("Failed to build system message")
-                                .into()
-                        }
-                        _ => {
-                            panic!("Invalid role type(only 'user', 'assistant', 'system' are allowed)")
-                        }
-                    }
+            let message = match input.role {
+                MessageRole::System => {
+                    ChatCompletionRequestSystemMessageArgs::default()
+                        .content(input.text())
+                        .build()?
+                        .into()
                 }
-
-                None => {
-                    panic!("Role not found")
+                MessageRole::User => Self::user_message(&input)?,
+                MessageRole::Assistant => {
+                    let mut args = ChatCompletionRequestAssistantMessageArgs::default();
+                    let text = input.text();
+                    if !text.is_empty() || input.tool_calls.is_empty() {
+                        args.content(text);
+                    }
+                    if !input.tool_calls.is_empty() {
+                        args.tool_calls(
+                            input.tool_calls
+                                .iter()
+                                .map(|call| ChatCompletionMessageToolCall {
+                                    id: call.id.clone(),
+                                    r#type: ChatCompletionToolType::Function,
+                                    function: FunctionCall {
+                                        name: call.name.clone(),
+                                        arguments: serde_json::to_string(&call.arguments).unwrap_or_default(),
+                                    },
+                                })
+                                .collect::<Vec<_>>(),
+                        );
+                    }
+                    args.build()?.into()
                 }
-
+                MessageRole::Tool => match &input.tool_call_id {
+                    Some(tool_call_id) => {
+                        ChatCompletionRequestToolMessageArgs::default()
+                            .content(input.text())
+                            .tool_call_id(tool_call_id.clone())
+                            .build()?
+                            .into()
+                    }
+                    // A tool result that answers no native tool call is reported as user input
+                    None => Self::user_message(&input)?,
+                },
             };
             outputs.push(message);
         }
-        outputs
+        Ok(outputs)
+    }
+
+    fn user_message(input: &ChatMessage) -> Result<ChatCompletionRequestMessage, OpenAIError> {
+        let has_images = input.content.iter().any(|part| matches!(part, ContentPart::Image { .. }));
+        let mut args = ChatCompletionRequestUserMessageArgs::default();
+        if has_images {
+            let parts = input.content
+                .iter()
+                .map(|part| match part {
+                    ContentPart::Text { text } => ChatCompletionRequestMessageContentPartText {
+                        text: text.clone(),
+                    }
+                    .into(),
+                    ContentPart::Image { url } => ChatCompletionRequestMessageContentPartImage {
+                        image_url: ImageUrl { url: url.clone(), detail: None },
+                    }
+                    .into(),
+                })
+                .collect::<Vec<ChatCompletionRequestUserMessageContentPart>>();
+            args.content(parts);
+        } else {
+            args.content(input.text());
+        }
+        Ok(args.build()?.into())
+    }
+
+    fn prepare_tools(&self, tools: Vec<ToolSchema>) -> Result<Vec<ChatCompletionTool>, OpenAIError> {
+        tools
+            .into_iter()
+            .map(|tool| {
+                let function = FunctionObjectArgs::default()
+                    .name(tool.name)
+                    .description(tool.description)
+                    .parameters(tool.parameters)
+                    .build()?;
+                ChatCompletionToolArgs::default().function(function).build()
+            })
+            .collect()
+    }
+}
+
+/// Tool call fragments accumulated over a stream, keyed by their `index`.
+#[derive(Default)]
+struct PartialToolCall {
+    id: String,
+    name: String,
+    arguments: String,
+}
+
+impl PartialToolCall {
+    fn assemble(self) -> ToolCall {
+        let parsed = match serde_json::from_str::<Value>(&self.arguments) {
+            // Tools without parameters may be called without arguments
+            _ if self.arguments.trim().is_empty() => Ok(HashMap::new()),
+            Ok(Value::Object(map)) => Ok(map.into_iter().collect()),
+            Ok(other) => Err(format!("the arguments must be a JSON object, got {}", other)),
+            Err(err) => Err(format!("the arguments are not valid JSON ({}): {}", err, self.arguments)),
+        };
+        let (arguments, arguments_error) = match parsed {
+            Ok(arguments) => (arguments, None),
+            Err(err) => {
+                warn!("Invalid arguments for tool call {}: {}", self.name, self.arguments);
+                (HashMap::new(), Some(err))
+            }
+        };
+        ToolCall {
+            id: self.id,
+            name: self.name,
+            arguments,
+            arguments_error,
+        }
     }
 }
 
@@ -125,42 +420,117 @@ impl Model for OpenAIModel {
 
     async fn async_generate_stream(
         &self,
-        messages: Vec<HashMap<String, String>>,
+        messages: Vec<ChatMessage>,
+        tools: Vec<ToolSchema>,
     ) -> Result<
-        Pin<Box<dyn futures::Stream<Item=Result<Bytes, Infallible>> + Send>>,
-        (StatusCode, String),
+        Pin<Box<dyn futures::Stream<Item=Result<ModelDelta, ModelError>> + Send>>,
+        ModelError,
     > {
         // 사용자 메시지 구성
-        let input_messages = self.prepare_inputs(messages);
+        let input_messages = self.prepare_inputs(messages)?;
         // 스트리밍 요청 생성
-        let request = CreateChatCompletionRequestArgs::default()
+        let mut request_args = CreateChatCompletionRequestArgs::default();
+        request_args
             .model(&self.model_name)
             .messages(input_messages)
             .stream(true)
-            .build()
-            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+            .stream_options(ChatCompletionStreamOptions { include_usage: true });
+        // OpenAI rejects an empty `tools` array
+        if !tools.is_empty() {
+            request_args.tools(self.prepare_tools(tools)?);
+        }
+        let request = request_args.build()?;
 
-        let stream = self.client
+        let mut stream = self.client
             .chat()
             .create_stream(request)
-            .await
-            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+            .await?;
 
-        let body_stream: BoxStream<Result<Bytes, Infallible>> = stream
-            .map(|chunk_result| -> Result<Bytes, Infallible> {
+        let body_stream = stream! {
+            let mut tool_calls: Vec<PartialToolCall> = Vec::new();
+            while let Some(chunk_result) = stream.next().await {
                 match chunk_result {
                     Ok(chunk) => {
-                        let text = chunk.choices[0].clone()
-                            .delta
-                            .content
-                            .unwrap_or_default();
-                        Ok(Bytes::from(text))
+                        // With `include_usage`, the last chunk carries the usage and no choices
+                        if let Some(usage) = chunk.usage {
+                            yield Ok(ModelDelta::Usage(TokenUsage::new(
+                                usage.prompt_tokens as usize,
+                                usage.completion_tokens as usize,
+                            )));
+                        }
+                        let Some(choice) = chunk.choices.into_iter().next() else {
+                            continue;
+                        };
+                        if let Some(text) = choice.delta.content {
+                            yield Ok(ModelDelta::Text(text));
+                        }
+                        for delta in choice.delta.tool_calls.unwrap_or_default() {
+                            let index = delta.index as usize;
+                            if tool_calls.len() <= index {
+                                tool_calls.resize_with(index + 1, PartialToolCall::default);
+                            }
+                            let call = &mut tool_calls[index];
+                            if let Some(id) = delta.id {
+                                call.id = id;
+                            }
+                            if let Some(function) = delta.function {
+                                call.name.push_str(&function.name.unwrap_or_default());
+                                call.arguments.push_str(&function.arguments.unwrap_or_default());
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        // The event source keeps reporting the same failure, stop at the first one
+                        yield Err(ModelError::from(e));
+                        return;
                     }
-                    Err(e) => Ok(Bytes::from(format!("\n[Error: {}]\n", e))),
                 }
-            })
-            .boxed();
+            }
+            if !tool_calls.is_empty() {
+                yield Ok(ModelDelta::ToolCalls(
+                    tool_calls.into_iter().map(PartialToolCall::assemble).collect(),
+                ));
+            }
+        };
 
         Ok(Box::pin(body_stream))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble(arguments: &str) -> ToolCall {
+        PartialToolCall {
+            id: "call_1".to_string(),
+            name: "web_search".to_string(),
+            arguments: arguments.to_string(),
+        }
+        .assemble()
+    }
+
+    #[test]
+    fn tool_call_arguments_are_parsed() {
+        let call = assemble(r#"{"query": "rust"}"#);
+        assert_eq!(call.arguments, HashMap::from([("query".to_string(), Value::from("rust"))]));
+        assert_eq!(call.arguments_error, None);
+
+        let call = assemble("");
+        assert!(call.arguments.is_empty());
+        assert_eq!(call.arguments_error, None);
+    }
+
+    #[test]
+    fn unreadable_tool_call_arguments_are_kept_as_an_error() {
+        let call = assemble(r#"{"query": "rust""#);
+        assert!(call.arguments.is_empty());
+        assert_eq!(
+            call.arguments_error.as_deref(),
+            Some(r#"the arguments are not valid JSON (EOF while parsing an object at line 1 column 16): {"query": "rust""#)
+        );
+
+        let call = assemble(r#""rust""#);
+        assert_eq!(call.arguments_error.as_deref(), Some(r#"the arguments must be a JSON object, got "rust""#));
+    }
+}
@@ -1,3 +1,127 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::memory::{Timing, TokenUsage};
+
+/// Result of an action, as shown to the model and reported to API clients.
+#[derive(Clone, Debug, Serialize)]
 pub struct Observation {
+    /// Text shown to the model.
     pub result: String,
-}
\ No newline at end of file
+    pub status: ObservationStatus,
+    /// Structured form of the result, when the action has one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+    /// Set when `result` was shortened to fit in the model's context.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncation: Option<Truncation>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+    /// Time the action took, set by the agent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timing: Option<Timing>,
+    /// Tokens the action used, e.g. in the run of a managed agent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_usage: Option<TokenUsage>,
+    /// Id of the tool call the observation answers, set by the agent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ObservationStatus {
+    Success,
+    Error,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Truncation {
+    /// Length of the full result, in characters.
+    pub original_length: usize,
+    /// Length it was cut down to, in characters.
+    pub max_length: usize,
+}
+
+/// Image or file produced by an action.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Attachment {
+    pub kind: AttachmentKind,
+    /// Where to find the content: an http(s) URL, a data URI or a file path.
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AttachmentKind {
+    /// Shown to the model along with the observation.
+    Image,
+    File,
+}
+
+impl Observation {
+    pub fn success(result: impl Into<String>) -> Self {
+        Self::new(result.into(), ObservationStatus::Success)
+    }
+
+    /// An action that failed; `message` tells the model what went wrong.
+    pub fn error(message: impl Into<String>) -> Self {
+        Self::new(message.into(), ObservationStatus::Error)
+    }
+
+    fn new(result: String, status: ObservationStatus) -> Self {
+        Self {
+            result,
+            status,
+            data: None,
+            truncation: None,
+            attachments: Vec::new(),
+            timing: None,
+            token_usage: None,
+            tool_call_id: None,
+        }
+    }
+
+    pub fn with_data(mut self, data: Value) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    pub fn with_attachment(mut self, attachment: Attachment) -> Self {
+        self.attachments.push(attachment);
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.status == ObservationStatus::Error
+    }
+
+    /// URLs of the attached images.
+    pub fn images(&self) -> Vec<String> {
+        self.attachments
+            .iter()
+            .filter(|attachment| attachment.kind == AttachmentKind::Image)
+            .map(|attachment| attachment.url.clone())
+            .collect()
+    }
+
+    /// Shortens `result` to about `max_length` characters, keeping its beginning and end.
+    pub fn truncate(&mut self, max_length: usize) {
+        let original_length = self.result.chars().count();
+        if original_length <= max_length {
+            return;
+        }
+        let head: String = self.result.chars().take(max_length / 2).collect();
+        let tail: String = self.result.chars().skip(original_length - max_length / 2).collect();
+        self.result = format!(
+            "{}\n..._This content has been truncated to stay below {} characters_...\n{}",
+            head, max_length, tail
+        );
+        self.truncation = Some(Truncation {
+            original_length,
+            max_length,
+        });
+    }
+}
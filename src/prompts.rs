@@ -1,6 +1,7 @@
+use minijinja::{Environment, UndefinedBehavior};
 use serde::Deserialize;
+use serde_json::Value;
 use std::fs;
-use serde_yaml;
 
 #[derive(Debug, Deserialize)]
 pub struct Prompt {
@@ -35,3 +36,12 @@ pub fn load_config(file_path: &str) -> Prompt {
     serde_yaml::from_str(&file_content)
         .expect("YAML 파일 파싱에 실패했습니다.")
 }
+
+/// Renders a Jinja prompt template. Variables missing from `variables` are an error.
+pub fn populate_template(template: &str, variables: &Value) -> Result<String, minijinja::Error> {
+    let mut env = Environment::new();
+    env.set_undefined_behavior(UndefinedBehavior::Strict);
+    // Templates use Python methods such as `dict.values()`
+    env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+    env.render_str(template, variables)
+}
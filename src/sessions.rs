@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::Mutex;
+use tracing::info;
+
+use crate::memory::{AgentMemory, Step, SystemPromptStep};
+
+/// Keeps one `AgentMemory` per session so that concurrent conversations don't share state.
+///
+/// Sessions that sat idle for too long, and the least recently used ones past the capacity, are
+/// forgotten as new sessions come in. Sessions with a run in progress are kept.
+pub struct SessionStore {
+    system_prompt: String,
+    sessions: Mutex<HashMap<String, Session>>,
+    idle_timeout: Option<Duration>,
+    max_sessions: Option<usize>,
+}
+
+struct Session {
+    memory: Arc<Mutex<AgentMemory>>,
+    last_used: Instant,
+}
+
+impl Session {
+    /// Whether a run, or anyone else, holds the memory.
+    fn in_use(&self) -> bool {
+        Arc::strong_count(&self.memory) > 1
+    }
+}
+
+impl SessionStore {
+    pub fn new(system_prompt: impl Into<String>) -> Self {
+        Self {
+            system_prompt: system_prompt.into(),
+            sessions: Mutex::new(HashMap::new()),
+            idle_timeout: None,
+            max_sessions: None,
+        }
+    }
+
+    /// Forgets sessions unused for `idle_timeout`, and keeps at most `max_sessions` of them;
+    /// `None` lifts the limit.
+    pub fn with_limits(mut self, idle_timeout: Option<Duration>, max_sessions: Option<usize>) -> Self {
+        self.idle_timeout = idle_timeout;
+        self.max_sessions = max_sessions;
+        self
+    }
+
+    /// System prompt of the sessions.
+    pub fn system_prompt(&self) -> &str {
+        &self.system_prompt
+    }
+
+    /// Sets the system prompt of the sessions created from now on.
+    pub fn set_system_prompt(&mut self, system_prompt: String) {
+        self.system_prompt = system_prompt;
+    }
+
+    /// Returns the memory of `session_id`, creating an empty one on first use.
+    pub async fn get(&self, session_id: &str) -> Arc<Mutex<AgentMemory>> {
+        let mut sessions = self.sessions.lock().await;
+        if !sessions.contains_key(session_id) {
+            self.evict(&mut sessions);
+            info!("Creating memory for session: {}", session_id);
+        }
+        let session = sessions.entry(session_id.to_string()).or_insert_with(|| Session {
+            memory: self.detached(),
+            last_used: Instant::now(),
+        });
+        session.last_used = Instant::now();
+        session.memory.clone()
+    }
+
+    /// Returns an empty memory that belongs to no session.
+    pub fn detached(&self) -> Arc<Mutex<AgentMemory>> {
+        Arc::new(Mutex::new(AgentMemory {
+            system_prompt: SystemPromptStep { system_prompt: self.system_prompt.clone() },
+            steps: vec![],
+        }))
+    }
+
+    /// Returns the memory of `session_id` if the session exists.
+    pub async fn find(&self, session_id: &str) -> Option<Arc<Mutex<AgentMemory>>> {
+        self.sessions.lock().await.get(session_id).map(|session| session.memory.clone())
+    }
+
+    /// Creates the memory of `session_id` holding `steps`, unless the session exists.
+    /// Returns whether it did.
+    pub async fn restore(&self, session_id: &str, steps: Vec<Step>) -> bool {
+        let mut sessions = self.sessions.lock().await;
+        if sessions.contains_key(session_id) {
+            return false;
+        }
+        self.evict(&mut sessions);
+        info!("Restoring memory for session: {} ({} steps)", session_id, steps.len());
+        let memory = self.detached();
+        memory.lock().await.steps = steps;
+        sessions.insert(session_id.to_string(), Session { memory, last_used: Instant::now() });
+        true
+    }
+
+    /// Forgets the memory of `session_id`. A run in progress keeps it until it ends.
+    pub async fn remove(&self, session_id: &str) {
+        if self.sessions.lock().await.remove(session_id).is_some() {
+            info!("Removed memory of session: {}", session_id);
+        }
+    }
+
+    /// Makes room for a new session: forgets the idle sessions past their time, then the least
+    /// recently used ones past the capacity.
+    fn evict(&self, sessions: &mut HashMap<String, Session>) {
+        if let Some(idle_timeout) = self.idle_timeout {
+            sessions.retain(|session_id, session| {
+                let keep = session.in_use() || session.last_used.elapsed() < idle_timeout;
+                if !keep {
+                    info!("Forgetting idle session: {}", session_id);
+                }
+                keep
+            });
+        }
+        let Some(max_sessions) = self.max_sessions else {
+            return;
+        };
+        let excess = (sessions.len() + 1).saturating_sub(max_sessions);
+        if excess == 0 {
+            return;
+        }
+        let mut idle: Vec<_> = sessions
+            .iter()
+            .filter(|(_, session)| !session.in_use())
+            .map(|(session_id, session)| (session.last_used, session_id.clone()))
+            .collect();
+        idle.sort();
+        for (_, session_id) in idle.into_iter().take(excess) {
+            info!("Forgetting least recently used session: {}", session_id);
+            sessions.remove(&session_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn idle_sessions_are_forgotten() {
+        let store = SessionStore::new("").with_limits(Some(Duration::from_millis(20)), None);
+        store.get("idle").await;
+        let running = store.get("running").await.lock_owned().await;
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        store.get("new").await;
+        assert!(store.find("idle").await.is_none());
+        assert!(store.find("running").await.is_some());
+        drop(running);
+        assert!(store.find("new").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_sessions_make_room() {
+        let store = SessionStore::new("").with_limits(None, Some(2));
+        store.get("a").await;
+        store.get("b").await;
+        store.get("a").await;
+
+        store.get("c").await;
+        assert!(store.find("a").await.is_some());
+        assert!(store.find("b").await.is_none());
+
+        // Sessions with a run in progress are kept, even past the capacity
+        let running = store.get("a").await.lock_owned().await;
+        assert!(store.restore("d", vec![]).await);
+        assert!(store.find("a").await.is_some());
+        assert!(store.find("c").await.is_none());
+        drop(running);
+    }
+}
@@ -0,0 +1,258 @@
+//! WebSocket sessions: a client sends tasks and answers over one connection, and receives the
+//! events of the runs as JSON text messages.
+use std::sync::Arc;
+
+use axum::{
+    extract::ws::{Message, WebSocket, WebSocketUpgrade},
+    extract::{Path, State},
+    response::Response,
+};
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tracing::{error, info};
+
+use crate::agents::AgentStream;
+use crate::events::AgentEvent;
+use crate::AppState;
+
+/// What a client can send.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    /// Starts a run, unless one is in progress.
+    Task {
+        task: String,
+        /// Clears the session's memory first.
+        #[serde(default)]
+        reset: bool,
+    },
+    /// Answers the pending `user_question` of the run.
+    Answer { answer: String },
+    /// Cancels the run in progress.
+    #[serde(alias = "interrupt")]
+    Cancel,
+}
+
+/// What the server sends besides agent events.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    /// The run ended; the session is ready for the next task.
+    RunFinished,
+    /// The run was cancelled at the client's request.
+    Cancelled,
+    /// A client message that could not be acted upon.
+    Rejected { message: String },
+}
+
+/// A run in progress: its events, and where to send the user's answers.
+struct ActiveRun {
+    events: AgentStream,
+    answers: UnboundedSender<String>,
+}
+
+/// `GET` on the WebSocket route: upgrades the connection to a session bound to `session_id`.
+pub async fn websocket(
+    State(state): State<Arc<AppState>>,
+    Path(session_id): Path<String>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    upgrade.on_upgrade(move |socket| serve(socket, state, session_id))
+}
+
+async fn serve(mut socket: WebSocket, state: Arc<AppState>, session_id: String) {
+    info!("WebSocket opened for session {}", session_id);
+    let mut run: Option<ActiveRun> = None;
+    loop {
+        let sent = tokio::select! {
+            message = socket.recv() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+                match serde_json::from_str::<ClientMessage>(&text) {
+                    Ok(message) => handle(message, &state, &session_id, &mut run, &mut socket).await,
+                    Err(err) => {
+                        let message = format!("Invalid message: {}", err);
+                        send(&mut socket, &ServerMessage::Rejected { message }).await
+                    }
+                }
+            }
+            event = next_event(&mut run) => match event {
+                Some(event) => send(&mut socket, &event).await,
+                None => {
+                    run = None;
+                    send(&mut socket, &ServerMessage::RunFinished).await
+                }
+            },
+        };
+        if let Err(err) = sent {
+            error!("WebSocket of session {} failed: {}", session_id, err);
+            break;
+        }
+    }
+    // Dropping the run's events cancels it
+    info!("WebSocket closed for session {}", session_id);
+}
+
+async fn handle(
+    message: ClientMessage,
+    state: &Arc<AppState>,
+    session_id: &str,
+    run: &mut Option<ActiveRun>,
+    socket: &mut WebSocket,
+) -> Result<(), axum::Error> {
+    let rejection = match message {
+        ClientMessage::Task { .. } if run.is_some() => "A run is already in progress",
+        ClientMessage::Task { task, reset } => {
+            let (answers, receiver) = mpsc::unbounded_channel();
+            // The run starts once it holds the session's memory, which another run may be
+            // using; waiting for it as part of the events keeps the socket responsive meanwhile.
+            let start = state.agent.clone().run(session_id.to_string(), task, vec![], reset, Some(receiver));
+            let events = Box::pin(stream::once(start).flatten());
+            *run = Some(ActiveRun { events, answers });
+            return Ok(());
+        }
+        ClientMessage::Answer { answer } => match run {
+            Some(run) => {
+                let _ = run.answers.send(answer);
+                return Ok(());
+            }
+            None => "No run is in progress",
+        },
+        ClientMessage::Cancel => match run.take() {
+            Some(_) => {
+                info!("Run of session {} cancelled by the client", session_id);
+                return send(socket, &ServerMessage::Cancelled).await;
+            }
+            None => "No run is in progress",
+        },
+    };
+    let message = rejection.to_string();
+    send(socket, &ServerMessage::Rejected { message }).await
+}
+
+/// The next event of the run in progress; never resolves while there is none.
+async fn next_event(run: &mut Option<ActiveRun>) -> Option<AgentEvent> {
+    match run {
+        Some(run) => run.events.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn send(socket: &mut WebSocket, message: &impl Serialize) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(message).map_err(axum::Error::new)?;
+    socket.send(Message::Text(text.into())).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use axum::{routing::get, Router};
+    use futures::SinkExt;
+    use serde_json::{json, Value};
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::{tungstenite, MaybeTlsStream, WebSocketStream};
+
+    use crate::actions::AskUserAction;
+    use crate::agents::tests::{response, ScriptedModel};
+    use crate::agents::Agent;
+    use crate::memory::TokenUsage;
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    /// Connects to the session `session` of a server running an agent with `model`.
+    async fn connect(model: &ScriptedModel) -> Client {
+        let agent = Agent::new(model.clone(), 3, vec![Box::new(AskUserAction::new())], false);
+        let state = Arc::new(AppState { agent: Arc::new(agent), agent_name: "agent-rs".to_string() });
+        let app = Router::new().route("/ws/{session_id}", get(websocket)).with_state(state);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let (client, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws/session", address)).await.unwrap();
+        client
+    }
+
+    async fn send(client: &mut Client, message: Value) {
+        client.send(tungstenite::Message::text(message.to_string())).await.unwrap();
+    }
+
+    /// The messages received up to the first one of type `until`, included.
+    async fn receive(client: &mut Client, until: &str) -> Vec<Value> {
+        let mut messages = vec![];
+        loop {
+            let message = tokio::time::timeout(Duration::from_secs(5), client.next())
+                .await
+                .unwrap_or_else(|_| panic!("no {} message after {:#?}", until, messages))
+                .unwrap()
+                .unwrap();
+            let message: Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+            let done = message["type"] == until;
+            messages.push(message);
+            if done {
+                return messages;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_ask_and_get_answers() {
+        let model = ScriptedModel::new(vec![
+            response("", Some(("ask_user", json!({ "question": "Which country?" }))), TokenUsage::new(10, 2)),
+            response("", Some(("final_answer", json!({ "answer": "Paris" }))), TokenUsage::new(20, 2)),
+        ]);
+        let mut client = connect(&model).await;
+
+        send(&mut client, json!({ "type": "answer", "answer": "France" })).await;
+        let rejected = receive(&mut client, "rejected").await;
+        assert_eq!(rejected, [json!({ "type": "rejected", "message": "No run is in progress" })]);
+
+        send(&mut client, json!({ "type": "task", "task": "What is the capital?" })).await;
+        let asked = receive(&mut client, "user_question").await;
+        assert_eq!(asked.last().unwrap()["question"], "Which country?");
+        send(&mut client, json!({ "type": "answer", "answer": "France" })).await;
+        let finished = receive(&mut client, "run_finished").await;
+        let answer = &finished[finished.len() - 2];
+        assert_eq!(answer["type"], "final_answer");
+        assert_eq!(answer["answer"], "Paris");
+        let observed = model.inputs.lock().unwrap()[1].last().unwrap().text();
+        assert!(observed.contains("France"), "{}", observed);
+    }
+
+    #[tokio::test]
+    async fn runs_are_one_at_a_time_and_can_be_interrupted() {
+        let model = ScriptedModel::hanging(vec![]);
+        let mut client = connect(&model).await;
+
+        send(&mut client, json!({ "type": "task", "task": "Take your time." })).await;
+        send(&mut client, json!({ "type": "task", "task": "Hurry up." })).await;
+        let rejected = receive(&mut client, "rejected").await;
+        assert_eq!(rejected.last().unwrap()["message"], "A run is already in progress");
+        model.called(1).await;
+
+        send(&mut client, json!({ "type": "interrupt" })).await;
+        assert_eq!(receive(&mut client, "cancelled").await.last().unwrap(), &json!({ "type": "cancelled" }));
+        send(&mut client, json!({ "type": "cancel" })).await;
+        assert_eq!(receive(&mut client, "rejected").await.last().unwrap()["message"], "No run is in progress");
+
+        // The session takes the next task once the interrupted run has let go of it
+        model.push(response("", Some(("final_answer", json!({ "answer": "Done" }))), TokenUsage::new(10, 2)));
+        send(&mut client, json!({ "type": "task", "task": "Hurry up." })).await;
+        let finished = receive(&mut client, "run_finished").await;
+        assert_eq!(finished[finished.len() - 2]["answer"], "Done");
+    }
+
+    #[tokio::test]
+    async fn invalid_messages_are_rejected() {
+        let mut client = connect(&ScriptedModel::new(vec![])).await;
+
+        send(&mut client, json!({ "type": "dance" })).await;
+        let rejected = receive(&mut client, "rejected").await;
+        let message = rejected[0]["message"].as_str().unwrap();
+        assert!(message.starts_with("Invalid message: "), "{}", message);
+    }
+}